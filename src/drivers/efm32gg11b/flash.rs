@@ -1,17 +1,21 @@
 use core::ops::{Add, Sub};
 
-use bytemuck::cast_slice;
 use efm32gg11b::MSC;
 
 use crate::{
-    hal::flash::ReadWrite,
-    utilities::memory::{IterableByOverlaps, Region},
+    hal::flash::{
+        merge_write, write_buffered, Background, EraseBlock, EraseRegion, Geometry, MergeError,
+        MergeScratch, Progress, ProgressHook, ReadWrite, ReportProgress, CHUNK_SIZE,
+    },
+    utilities::memory::Region,
 };
 
 use super::clocks::Clocks;
 
 pub struct Flash {
     msc: MSC,
+    progress: Option<ProgressHook<Address>>,
+    scratch: Option<&'static mut [u8]>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -38,6 +42,8 @@ pub enum Error {
     MemoryIsLocked,
     InvalidAddress,
     MisalignedAccess,
+    /// The buffer set through `MergeScratch` can't hold a page.
+    ScratchTooSmall,
 }
 
 impl Region<Address> for Map {
@@ -85,17 +91,21 @@ impl Flash {
             msc.lock.write(|w| w.bits(MSC_UNLOCK_CODE));
         }
         msc.writectrl.write(|w| w.wren().set_bit());
-        Self { msc }
+        Self { msc, progress: None, scratch: None }
     }
 
     fn is_busy(&self) -> bool { self.msc.status.read().busy().bit_is_set() }
 
     fn wait_until_not_busy(&self) { while self.is_busy() {} }
 
-    fn wait_until_ready_to_write(&self) {
-        while self.msc.status.read().wdataready().bit_is_clear() {}
+    fn report(&self, done: usize, total: Option<usize>, sector: Address) {
+        if let Some(hook) = self.progress {
+            hook(Progress { done, total, sector });
+        }
     }
 
+    fn is_ready_to_write(&self) -> bool { self.msc.status.read().wdataready().bit_is_set() }
+
     fn erase_page(&mut self, page: Page) -> nb::Result<(), Error> {
         if self.is_busy() {
             return Err(nb::Error::WouldBlock);
@@ -132,28 +142,6 @@ impl Flash {
             Ok(())
         }
     }
-
-    fn write_page(&mut self, bytes: &[u8], page: Page) -> nb::Result<(), Error> {
-        if bytes.len() != size::PAGE {
-            return Err(nb::Error::Other(Error::MisalignedAccess));
-        }
-        if self.is_busy() {
-            return Err(nb::Error::WouldBlock);
-        }
-        // We know this to be aligned, so it can't fail.
-        let words: &[u32] = cast_slice(bytes);
-
-        self.load_address(page.address())?;
-        for word in words {
-            self.wait_until_ready_to_write();
-            // Safety: Unsafe required to write the entire word at once to a register.
-            unsafe { self.msc.wdata.write(|w| w.bits(*word)) }
-            self.msc.writecmd.write(|w| w.writeonce().set_bit());
-            self.wait_until_not_busy();
-        }
-
-        Ok(())
-    }
 }
 
 impl Drop for Flash {
@@ -202,27 +190,30 @@ impl ReadWrite for Flash {
             return Err(nb::Error::WouldBlock);
         }
 
-        for (block, page, address) in Map::pages().overlaps(bytes, address) {
-            let page_data = &mut [0u8; size::PAGE];
-            nb::block!(self.read(page.address(), page_data))?;
-            let offset_into_page = address.0.saturating_sub(page.address().0) as usize;
-            page_data
-                .iter_mut()
-                .skip(offset_into_page)
-                .zip(block)
-                .for_each(|(byte, input)| *byte = *input);
-            nb::block!(self.erase_page(page))?;
-            nb::block!(self.write_page(page_data, page))?;
-        }
-
-        Ok(())
+        // A page always fits the default scratch.
+        let mut fallback = [0u8; size::PAGE];
+        let (mut scratch, hook) = (self.scratch.take(), self.progress);
+        let result = merge_write(
+            self,
+            address,
+            bytes,
+            scratch.as_deref_mut().unwrap_or(&mut fallback),
+            hook,
+        );
+        self.scratch = scratch;
+        result.map_err(|error| {
+            nb::Error::Other(match error {
+                MergeError::Flash(error) => error,
+                MergeError::ScratchTooSmall { .. } => Error::ScratchTooSmall,
+                MergeError::OutOfBounds => Error::InvalidAddress,
+            })
+        })
     }
 
     fn range(&self) -> (Self::Address, Self::Address) {
         (Address(0), Address(0) + count::PAGES * size::PAGE)
     }
 
-
     fn erase(&mut self) -> nb::Result<(), Self::Error> {
         if self.is_busy() {
             return Err(nb::Error::WouldBlock);
@@ -233,10 +224,13 @@ impl ReadWrite for Flash {
         // that we write bits that leave the peripheral in a known and
         // correct state.
         unsafe { self.msc.masslock.write(|w| w.bits(MSC_MASS_ERASE_CODE)) }
+        // Each command erases one of the two flash banks.
         self.msc.writecmd.write(|w| w.erasemain0().set_bit());
         self.wait_until_not_busy();
+        self.report(Map::size() / 2, Some(Map::size()), Address(0));
         self.msc.writecmd.write(|w| w.erasemain1().set_bit());
         self.wait_until_not_busy();
+        self.report(Map::size(), Some(Map::size()), Address((Map::size() / 2) as u32));
         unsafe { self.msc.masslock.write(|w| w.bits(0)) }
         Ok(())
     }
@@ -246,24 +240,82 @@ impl ReadWrite for Flash {
         address: Self::Address,
         blocks: I,
     ) -> Result<(), Self::Error> {
-        const TRANSFER_SIZE: usize = KB!(64);
-        assert!(TRANSFER_SIZE % N == 0);
-        let mut transfer_array = [0x00u8; TRANSFER_SIZE];
-        let mut memory_index = 0usize;
-
-        for block in blocks {
-            let slice = &mut transfer_array
-                [(memory_index % TRANSFER_SIZE)..((memory_index % TRANSFER_SIZE) + N)];
-            slice.clone_from_slice(&block);
-            memory_index += N;
-
-            if memory_index % TRANSFER_SIZE == 0 {
-                nb::block!(self.write(address + (memory_index - TRANSFER_SIZE), &transfer_array))?;
-                transfer_array.iter_mut().for_each(|b| *b = 0x00u8);
-            }
+        // Progress is reported for the whole transfer, rather than per write.
+        let hook = self.progress.take();
+        let result = write_buffered(self, address, blocks, &mut [0x00u8; CHUNK_SIZE], hook);
+        self.progress = hook;
+        result
+    }
+}
+
+impl EraseRegion for Flash {
+    fn erase_range(&mut self, start: Address, end: Address) -> nb::Result<(), Self::Error> {
+        if (start > end) || (end > self.range().1) {
+            return Err(nb::Error::Other(Error::MemoryNotReachable));
+        }
+        if self.is_busy() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let overlapping =
+            || Map::pages().filter(|p| (p.address() < end) && (start < p.address() + size::PAGE));
+        let total = overlapping().count() * size::PAGE;
+        for (index, page) in overlapping().enumerate() {
+            nb::block!(self.erase_page(page))?;
+            self.report((index + 1) * size::PAGE, Some(total), page.address());
+        }
+        Ok(())
+    }
+}
+
+impl Geometry for Flash {
+    fn erase_block_count(&self) -> usize { count::PAGES }
+
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Address>> {
+        Map::pages().nth(index).map(|page| EraseBlock { start: page.address(), size: size::PAGE })
+    }
+
+    /// Writes are performed a word at a time.
+    fn write_alignment(&self) -> usize { 4 }
+}
+
+impl ReportProgress for Flash {
+    fn set_progress_hook(&mut self, hook: Option<ProgressHook<Address>>) { self.progress = hook; }
+}
+
+impl MergeScratch for Flash {
+    fn set_scratch_buffer(&mut self, scratch: Option<&'static mut [u8]>) { self.scratch = scratch; }
+}
+
+impl Background for Flash {
+    fn cycle_in_progress(&mut self) -> Result<bool, Error> { Ok(self.is_busy()) }
+
+    fn begin_erase(&mut self, block: EraseBlock<Address>) -> nb::Result<(), Error> {
+        let page = Map::pages()
+            .find(|p| (p.address() == block.start) && (block.size == size::PAGE))
+            .ok_or(Error::InvalidAddress)?;
+        if self.is_busy() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.load_address(page.address())?;
+        self.msc.writecmd.write(|w| w.erasepage().set_bit());
+        Ok(())
+    }
+
+    /// Programs a single word.
+    fn begin_program(&mut self, address: Address, bytes: &[u8]) -> nb::Result<usize, Error> {
+        if (address.0 & 0b11 != 0) || (bytes.len() < 4) {
+            return Err(nb::Error::Other(Error::MisalignedAccess));
+        }
+        if self.is_busy() || !self.is_ready_to_write() {
+            return Err(nb::Error::WouldBlock);
         }
-        let remainder = &transfer_array[0..(memory_index % TRANSFER_SIZE)];
-        nb::block!(self.write(address + (memory_index - remainder.len()), &remainder))
+        self.load_address(address)?;
+        let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        // Safety: Unsafe required to write the entire word at once to a register.
+        unsafe { self.msc.wdata.write(|w| w.bits(word)) }
+        self.msc.writecmd.write(|w| w.writeonce().set_bit());
+        Ok(4)
     }
 }
 
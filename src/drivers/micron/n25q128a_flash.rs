@@ -1,9 +1,15 @@
 //! Device driver for the [Micron N24q128a](../../../../../../documentation/hardware/micron_flash.pdf#page=0)
 use crate::{
-    hal::{flash::ReadWrite, qspi, time},
+    hal::{
+        flash::{
+            merge_write, write_buffered, Background, EraseBlock, EraseRegion, Geometry, MergeError,
+            MergeScratch, Progress, ProgressHook, ReadWrite, ReportProgress,
+        },
+        qspi, time,
+    },
     utilities::{
-        bitwise::{BitFlags, SliceBitSubset},
-        memory::{self, IterableByOverlaps, Region},
+        bitwise::BitFlags,
+        memory::{self, Region},
     },
 };
 use core::{
@@ -126,6 +132,14 @@ const NUMBER_OF_SECTORS: usize = 256;
 const NUMBER_OF_SUBSECTORS: usize = NUMBER_OF_SECTORS * SUBSECTORS_PER_SECTOR;
 const NUMBER_OF_PAGES: usize = NUMBER_OF_SUBSECTORS * PAGES_PER_SUBSECTOR;
 
+/// Interval between status polls (and progress reports) during a bulk
+/// erase, which takes tens of seconds.
+const BULK_ERASE_POLL_INTERVAL: time::Milliseconds = time::Milliseconds(100);
+
+/// Scratch used to merge writes when no buffer is set through `MergeScratch`,
+/// enough to rewrite any subsector.
+const DEFAULT_SCRATCH_SIZE: usize = SUBSECTOR_SIZE;
+
 /// MicronN25q128a driver, generic over a QSPI programmed in indirect mode
 pub struct MicronN25q128a<QSPI, NOW>
 where
@@ -134,6 +148,8 @@ where
 {
     qspi: QSPI,
     timeout: Option<time::Milliseconds>,
+    progress: Option<ProgressHook<Address>>,
+    scratch: Option<&'static mut [u8]>,
     _marker: PhantomData<NOW>,
 }
 
@@ -144,11 +160,14 @@ pub enum Error {
     WrongManufacturerId,
     MisalignedAccess,
     AddressOutOfRange,
+    /// Rewriting part of a subsector needs a larger scratch buffer, see `MergeScratch`.
+    ScratchTooSmall,
 }
 
 #[derive(Debug, Clone, Copy)]
 enum Command {
     PageProgram = 0x02,
+    SubsectorErase = 0x20,
     Read = 0x03,
     WriteDisable = 0x04,
     ReadStatus = 0x05,
@@ -185,7 +204,14 @@ where
             Self::execute_command(&mut self.qspi, Command::WriteEnable, None, CommandData::None)?;
             Self::execute_command(&mut self.qspi, Command::BulkErase, None, CommandData::None)?;
             Self::execute_command(&mut self.qspi, Command::WriteDisable, None, CommandData::None)?;
-            while Self::status(&mut self.qspi)?.write_in_progress {}
+            // Progress can't be measured during a bulk erase, so the device is
+            // only polled (and progress reported, without a total) at intervals.
+            while Self::status(&mut self.qspi)?.write_in_progress {
+                let polled = NOW::now();
+                while NOW::now() - polled < BULK_ERASE_POLL_INTERVAL {}
+                self.report(0, None, BASE_ADDRESS);
+            }
+            self.report(MEMORY_SIZE, Some(MEMORY_SIZE), BASE_ADDRESS);
             Ok(())
         }
     }
@@ -195,30 +221,23 @@ where
             return Err(nb::Error::WouldBlock);
         }
 
-        for (bytes, sector, address) in MemoryMap::sectors().overlaps(bytes, address) {
-            let offset_into_sector = address - sector.location();
-            let mut sector_data = [0x00u8; SECTOR_SIZE];
-            block!(self.read(sector.location(), &mut sector_data))?;
-            if bytes.is_subset_of(&sector_data[offset_into_sector..]) {
-                for (bytes, page, address) in sector.pages().overlaps(bytes, address) {
-                    block!(self.write_page(&page, bytes, address))?;
-                }
-            } else {
-                block!(self.erase_sector(&sector))?;
-                // "merge" the preexisting data with the new write.
-                sector_data
-                    .iter_mut()
-                    .skip(offset_into_sector)
-                    .zip(bytes)
-                    .for_each(|(a, b)| *a = *b);
-                for (bytes, page, address) in
-                    sector.pages().overlaps(&sector_data, sector.location())
-                {
-                    block!(self.write_page(&page, bytes, address))?;
-                }
-            }
-        }
-        Ok(())
+        let mut fallback = [0x00u8; DEFAULT_SCRATCH_SIZE];
+        let (mut scratch, hook) = (self.scratch.take(), self.progress);
+        let result = merge_write(
+            self,
+            address,
+            bytes,
+            scratch.as_deref_mut().unwrap_or(&mut fallback),
+            hook,
+        );
+        self.scratch = scratch;
+        result.map_err(|error| {
+            nb::Error::Other(match error {
+                MergeError::Flash(error) => error,
+                MergeError::ScratchTooSmall { .. } => Error::ScratchTooSmall,
+                MergeError::OutOfBounds => Error::AddressOutOfRange,
+            })
+        })
     }
 
     fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
@@ -226,24 +245,11 @@ where
         address: Self::Address,
         blocks: I,
     ) -> Result<(), Self::Error> {
-        assert!(SECTOR_SIZE % N == 0);
-        let mut transfer_array = [0x00u8; SECTOR_SIZE];
-        let mut memory_index = 0usize;
-
-        for block in blocks {
-            let slice = &mut transfer_array
-                [(memory_index % SECTOR_SIZE)..((memory_index % SECTOR_SIZE) + N)];
-            slice.clone_from_slice(&block);
-            memory_index += N;
-
-            if memory_index % SECTOR_SIZE == 0 {
-                nb::block!(self.write(address + (memory_index - SECTOR_SIZE), &transfer_array))?;
-                transfer_array.iter_mut().for_each(|b| *b = 0x00u8);
-            }
-        }
-        let remainder = &transfer_array[0..(memory_index % SECTOR_SIZE)];
-        nb::block!(self.write(address + (memory_index - remainder.len()), &remainder))?;
-        Ok(())
+        // Progress is reported for the whole transfer, rather than per write.
+        let hook = self.progress.take();
+        let result = write_buffered(self, address, blocks, &mut [0x00u8; PAGE_SIZE], hook);
+        self.progress = hook;
+        result
     }
 
     fn read(&mut self, address: Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
@@ -263,11 +269,134 @@ where
     fn label() -> &'static str { "Micron n25q128a (External)" }
 }
 
+impl<QSPI, NOW> EraseRegion for MicronN25q128a<QSPI, NOW>
+where
+    QSPI: qspi::Indirect,
+    NOW: time::Now,
+{
+    fn erase_range(&mut self, start: Address, end: Address) -> nb::Result<(), Self::Error> {
+        if (start > end) || (end > MemoryMap::end()) {
+            return Err(nb::Error::Other(Error::AddressOutOfRange));
+        }
+        if Self::status(&mut self.qspi)?.write_in_progress {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let total = Self::erases(start, end).map(|(_, _, size)| size).sum();
+        let mut done = 0;
+        for (command, location, size) in Self::erases(start, end) {
+            block!(self.erase_at(command, location))?;
+            done += size;
+            self.report(done, Some(total), location);
+        }
+        Ok(())
+    }
+}
+
+impl<QSPI, NOW> Geometry for MicronN25q128a<QSPI, NOW>
+where
+    QSPI: qspi::Indirect,
+    NOW: time::Now,
+{
+    /// Subsectors are the smallest erasable blocks, which keeps merged
+    /// writes within the default scratch buffer.
+    fn erase_block_count(&self) -> usize { NUMBER_OF_SUBSECTORS }
+
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Address>> {
+        (index < NUMBER_OF_SUBSECTORS)
+            .then(|| EraseBlock { start: Subsector(index).location(), size: Subsector::size() })
+    }
+
+    /// Page programming can start at any byte.
+    fn write_alignment(&self) -> usize { 1 }
+}
+
+impl<QSPI, NOW> ReportProgress for MicronN25q128a<QSPI, NOW>
+where
+    QSPI: qspi::Indirect,
+    NOW: time::Now,
+{
+    fn set_progress_hook(&mut self, hook: Option<ProgressHook<Address>>) { self.progress = hook; }
+}
+
+impl<QSPI, NOW> MergeScratch for MicronN25q128a<QSPI, NOW>
+where
+    QSPI: qspi::Indirect,
+    NOW: time::Now,
+{
+    fn set_scratch_buffer(&mut self, scratch: Option<&'static mut [u8]>) { self.scratch = scratch; }
+}
+
+impl<QSPI, NOW> Background for MicronN25q128a<QSPI, NOW>
+where
+    QSPI: qspi::Indirect,
+    NOW: time::Now,
+{
+    fn cycle_in_progress(&mut self) -> Result<bool, Error> {
+        Ok(block!(Self::status(&mut self.qspi))?.write_in_progress)
+    }
+
+    fn begin_erase(&mut self, block: EraseBlock<Address>) -> nb::Result<(), Error> {
+        let subsector = Subsector::at(block.start)
+            .filter(|s| (s.location() == block.start) && (block.size == Subsector::size()))
+            .ok_or(Error::AddressOutOfRange)?;
+        if self.cycle_in_progress()? {
+            return Err(nb::Error::WouldBlock);
+        }
+        block!(Self::execute_command(
+            &mut self.qspi,
+            Command::WriteEnable,
+            None,
+            CommandData::None
+        ))?;
+        block!(Self::execute_command(
+            &mut self.qspi,
+            Command::SubsectorErase,
+            Some(subsector.location()),
+            CommandData::None
+        ))?;
+        Ok(())
+    }
+
+    /// Programs up to the end of the page holding the address.
+    fn begin_program(&mut self, address: Address, bytes: &[u8]) -> nb::Result<usize, Error> {
+        if address >= MemoryMap::end() {
+            return Err(nb::Error::Other(Error::AddressOutOfRange));
+        }
+        if self.cycle_in_progress()? {
+            return Err(nb::Error::WouldBlock);
+        }
+        let size = bytes.len().min(PAGE_SIZE - (address.0 as usize % PAGE_SIZE));
+        block!(Self::execute_command(
+            &mut self.qspi,
+            Command::WriteEnable,
+            None,
+            CommandData::None
+        ))?;
+        block!(Self::execute_command(
+            &mut self.qspi,
+            Command::PageProgram,
+            Some(address),
+            CommandData::Write(&bytes[..size])
+        ))?;
+        Ok(size)
+    }
+
+    /// Gives up after the driver timeout, if any.
+    fn wait_until_idle(&mut self) -> Result<(), Error> { block!(self.wait_until_write_complete()) }
+}
+
 impl<QSPI, NOW> MicronN25q128a<QSPI, NOW>
 where
     QSPI: qspi::Indirect,
     NOW: time::Now,
 {
+    fn report(&self, done: usize, total: Option<usize>, sector: Address) {
+        if let Some(hook) = self.progress {
+            hook(Progress { done, total, sector });
+        }
+    }
+
     fn wait_until_write_complete(&mut self) -> nb::Result<(), Error> {
         if let Some(timeout) = &self.timeout {
             let start = NOW::now();
@@ -332,56 +461,55 @@ where
 
     /// Blocks until flash ID read checks out, or until timeout
     pub fn new(qspi: QSPI) -> Result<Self, Error> {
-        let mut flash = Self { qspi, timeout: None, _marker: Default::default() };
+        let mut flash = Self {
+            qspi,
+            timeout: None,
+            progress: None,
+            scratch: None,
+            _marker: Default::default(),
+        };
         block!(flash.verify_id())?;
         Ok(flash)
     }
 
     pub fn with_timeout(qspi: QSPI, timeout: time::Milliseconds) -> Result<Self, Error> {
-        let mut flash = Self { qspi, timeout: Some(timeout), _marker: Default::default() };
+        let mut flash = Self {
+            qspi,
+            timeout: Some(timeout),
+            progress: None,
+            scratch: None,
+            _marker: Default::default(),
+        };
         block!(flash.verify_id())?;
         Ok(flash)
     }
 
-    fn erase_sector(&mut self, sector: &Sector) -> nb::Result<(), Error> {
-        if Self::status(&mut self.qspi)?.write_in_progress {
-            return Err(nb::Error::WouldBlock);
-        }
-        block!(Self::execute_command(
-            &mut self.qspi,
-            Command::WriteEnable,
-            None,
-            CommandData::None
-        ))?;
-        block!(Self::execute_command(
-            &mut self.qspi,
-            Command::SectorErase,
-            Some(sector.location()),
-            CommandData::None
-        ))?;
-        Ok(block!(self.wait_until_write_complete())?)
+    /// Sector or subsector erases covering every subsector overlapping a
+    /// range, erasing whole sectors at once where possible.
+    fn erases(start: Address, end: Address) -> impl Iterator<Item = (Command, Address, usize)> {
+        let overlapping = move |s: &Subsector| (s.location() < end) && (start < s.end());
+        MemoryMap::subsectors().filter(overlapping).filter_map(move |subsector| {
+            let sector = Sector(subsector.0 / SUBSECTORS_PER_SECTOR);
+            if (start <= sector.location()) && (sector.end() <= end) {
+                (subsector.location() == sector.location())
+                    .then(|| (Command::SectorErase, sector.location(), SECTOR_SIZE))
+            } else {
+                Some((Command::SubsectorErase, subsector.location(), SUBSECTOR_SIZE))
+            }
+        })
     }
 
-    fn write_page(&mut self, page: &Page, bytes: &[u8], address: Address) -> nb::Result<(), Error> {
-        if (address < page.location()) || (address + bytes.len() > page.end()) {
-            return Err(nb::Error::Other(Error::MisalignedAccess));
-        }
+    fn erase_at(&mut self, command: Command, location: Address) -> nb::Result<(), Error> {
         if Self::status(&mut self.qspi)?.write_in_progress {
             return Err(nb::Error::WouldBlock);
         }
-
         block!(Self::execute_command(
             &mut self.qspi,
             Command::WriteEnable,
             None,
             CommandData::None
         ))?;
-        block!(Self::execute_command(
-            &mut self.qspi,
-            Command::PageProgram,
-            Some(address),
-            CommandData::Write(&bytes)
-        ))?;
+        block!(Self::execute_command(&mut self.qspi, command, Some(location), CommandData::None))?;
         Ok(block!(self.wait_until_write_complete())?)
     }
 }
@@ -390,7 +518,7 @@ where
 mod test {
     use super::*;
     use crate::hal::doubles::{gpio::*, qspi::*, time::*};
-    use std::collections::VecDeque;
+    use std::{collections::VecDeque, sync::Mutex};
 
     type FlashToTest = MicronN25q128a<MockQspi, MockSysTick>;
     fn flash_to_test() -> FlashToTest {
@@ -464,6 +592,67 @@ mod test {
         assert_eq!(records[3].instruction, Some(Command::WriteDisable as u8));
     }
 
+    #[test]
+    fn range_erase_only_erases_overlapping_subsectors() {
+        // Given
+        let mut flash = flash_to_test();
+        let start = Address((2 * SECTOR_SIZE - 10) as u32);
+        let end = Address((3 * SECTOR_SIZE + 10) as u32);
+        let erases = [Some(Command::SubsectorErase as u8), Some(Command::SectorErase as u8)];
+
+        // When
+        flash.erase_range(start, end).unwrap();
+        let erased: Vec<_> = flash
+            .qspi
+            .command_records
+            .iter()
+            .filter(|r| erases.contains(&r.instruction))
+            .map(|r| (r.instruction, r.address))
+            .collect();
+
+        // Then
+        assert_eq!(
+            erased,
+            vec![
+                (
+                    Some(Command::SubsectorErase as u8),
+                    Some((2 * SECTOR_SIZE - SUBSECTOR_SIZE) as u32)
+                ),
+                (Some(Command::SectorErase as u8), Some(2 * SECTOR_SIZE as u32)),
+                (Some(Command::SubsectorErase as u8), Some(3 * SECTOR_SIZE as u32)),
+            ]
+        );
+        assert_eq!(
+            flash.erase_range(Address(0), MemoryMap::end() + 1),
+            Err(nb::Error::Other(Error::AddressOutOfRange))
+        );
+    }
+
+    #[test]
+    fn range_erase_reports_progress_per_erase() {
+        // Given
+        static REPORTS: Mutex<Vec<Progress<Address>>> = Mutex::new(Vec::new());
+        let mut flash = flash_to_test();
+        flash.set_progress_hook(Some(|progress| REPORTS.lock().unwrap().push(progress)));
+        let start = Address((2 * SECTOR_SIZE - 10) as u32);
+        let end = Address((3 * SECTOR_SIZE + 10) as u32);
+
+        // When
+        flash.erase_range(start, end).unwrap();
+
+        // Then
+        let total = Some(2 * SUBSECTOR_SIZE + SECTOR_SIZE);
+        let reports: Vec<_> = REPORTS.lock().unwrap().iter().map(|p| (p.done, p.total)).collect();
+        assert_eq!(
+            reports,
+            vec![
+                (SUBSECTOR_SIZE, total),
+                (SUBSECTOR_SIZE + SECTOR_SIZE, total),
+                (2 * SUBSECTOR_SIZE + SECTOR_SIZE, total)
+            ]
+        );
+    }
+
     #[test]
     fn write_capable_commands_yield_if_device_busy() {
         // Given
@@ -477,6 +666,34 @@ mod test {
         flash.qspi.to_read.push_back(vec![BUSY_WRITING_STATUS]);
     }
 
+    #[test]
+    fn writing_over_erased_memory_programs_pages_without_erasing() {
+        // Given
+        let mut flash = flash_to_test();
+        let address = Address(0x1000);
+        let data = [0xAAu8; PAGE_SIZE];
+        // Erased contents are read once to check the write, and again to merge it
+        flash.qspi.to_read.push_back(vec![0x00]);
+        flash.qspi.to_read.push_back(vec![0x00]);
+        flash.qspi.to_read.push_back(vec![0xFF; PAGE_SIZE]);
+        flash.qspi.to_read.push_back(vec![0x00]);
+        flash.qspi.to_read.push_back(vec![0xFF; PAGE_SIZE]);
+
+        // When
+        flash.write(address, &data).unwrap();
+        let records = &flash.qspi.command_records;
+
+        // Then
+        assert_eq!(records[0].instruction, Some(Command::ReadStatus as u8));
+        assert_eq!(records[2].instruction, Some(Command::Read as u8));
+        assert_eq!(records[4].instruction, Some(Command::Read as u8));
+        assert_eq!(records[7].instruction, Some(Command::WriteEnable as u8));
+        assert_eq!(records[8].instruction, Some(Command::PageProgram as u8));
+        assert_eq!(Some(address.0), records[8].address);
+        assert!(records[8].contains(&data));
+        assert!(records.iter().all(|r| r.instruction != Some(Command::SectorErase as u8)));
+    }
+
     #[test]
     fn page_program_command_sequence() {
         // Given
@@ -485,10 +702,11 @@ mod test {
         let data = [0xAAu8; PAGE_SIZE];
 
         // When
-        flash.write_page(&Page::at(address).unwrap(), &data, address).unwrap();
+        let accepted = flash.begin_program(address, &data).unwrap();
         let records = &flash.qspi.command_records;
 
         // Then
+        assert_eq!(accepted, PAGE_SIZE);
         assert_eq!(records[0].instruction, Some(Command::ReadStatus as u8));
         assert_eq!(records[1].instruction, Some(Command::WriteEnable as u8));
         assert_eq!(records[2].instruction, Some(Command::PageProgram as u8));
@@ -496,6 +714,92 @@ mod test {
         assert!(records[2].contains(&data));
     }
 
+    #[test]
+    fn rewriting_part_of_a_subsector_merges_through_the_default_scratch() {
+        // Given
+        let mut flash = flash_to_test();
+        let address = Address(0x1010);
+        let data = [0xAAu8; 16];
+
+        // When
+        flash.write(address, &data).unwrap();
+        let records = &flash.qspi.command_records;
+
+        // Then
+        let erase =
+            records.iter().position(|r| r.instruction == Some(Command::SubsectorErase as u8));
+        let program = records.iter().position(|r| r.contains(&data));
+        assert_eq!(records[erase.unwrap()].address, Some(0x1000));
+        assert_eq!(records[program.unwrap()].address, Some(address.0));
+        assert!(erase < program);
+        assert!(records.iter().all(|r| r.instruction != Some(Command::SectorErase as u8)));
+    }
+
+    #[test]
+    fn merged_writes_fail_cleanly_with_a_small_scratch_buffer() {
+        let mut flash = flash_to_test();
+        flash.set_scratch_buffer(Some(Box::leak(vec![0u8; PAGE_SIZE].into_boxed_slice())));
+
+        let result = flash.write(Address(0x1010), &[0xAAu8; 16]);
+
+        assert_eq!(result, Err(nb::Error::Other(Error::ScratchTooSmall)));
+        let records = &flash.qspi.command_records;
+        assert!(records.iter().all(|r| r.instruction != Some(Command::SubsectorErase as u8)));
+    }
+
+    #[test]
+    fn background_erase_and_program_start_without_waiting() {
+        // Given
+        const BUSY_WRITING_STATUS: u8 = 1;
+        let mut flash = flash_to_test();
+        let subsector = MemoryMap::subsectors().nth(2).unwrap();
+        let address = Address(0x1000 + PAGE_SIZE as u32 - 4);
+
+        // When
+        flash
+            .begin_erase(EraseBlock { start: subsector.location(), size: SUBSECTOR_SIZE })
+            .unwrap();
+        let accepted = flash.begin_program(address, &[0xAA; 16]).unwrap();
+        flash.qspi.to_read.push_back(vec![BUSY_WRITING_STATUS]);
+        let records = &flash.qspi.command_records;
+
+        // Then
+        assert_eq!(records[0].instruction, Some(Command::ReadStatus as u8));
+        assert_eq!(records[1].instruction, Some(Command::WriteEnable as u8));
+        assert_eq!(records[2].instruction, Some(Command::SubsectorErase as u8));
+        assert_eq!(records[2].address, Some(subsector.location().0));
+        assert_eq!(records[5].instruction, Some(Command::PageProgram as u8));
+        assert_eq!(records[5].address, Some(address.0));
+        assert_eq!(accepted, 4);
+        assert_eq!(records.len(), 6);
+        assert_eq!(flash.cycle_in_progress(), Ok(true));
+        assert_eq!(
+            flash.begin_erase(EraseBlock { start: Address(10), size: SUBSECTOR_SIZE }),
+            Err(nb::Error::Other(Error::AddressOutOfRange))
+        );
+    }
+
+    #[test]
+    fn background_operations_do_not_start_while_a_cycle_is_running() {
+        // Given
+        const BUSY_WRITING_STATUS: u8 = 1;
+        let mut flash = flash_to_test();
+        flash.qspi.to_read.push_back(vec![BUSY_WRITING_STATUS]);
+        flash.qspi.to_read.push_back(vec![BUSY_WRITING_STATUS]);
+        let subsector = MemoryMap::subsectors().nth(2).unwrap();
+
+        // When
+        let erase =
+            flash.begin_erase(EraseBlock { start: subsector.location(), size: SUBSECTOR_SIZE });
+        let program = flash.begin_program(Address(0x1000), &[0xAA; 16]);
+
+        // Then
+        assert_eq!(erase, Err(nb::Error::WouldBlock));
+        assert_eq!(program, Err(nb::Error::WouldBlock));
+        let records = &flash.qspi.command_records;
+        assert!(records.iter().all(|r| r.instruction == Some(Command::ReadStatus as u8)));
+    }
+
     #[test]
     fn subsector_read_command_sequence() {
         // Given
@@ -1,23 +1,40 @@
 //! Internal Flash controller for the STM32F4 family
 use crate::{
-    hal::flash::ReadWrite,
-    stm32pac::FLASH,
-    utilities::{
-        bitwise::SliceBitSubset,
-        memory::{self, IterableByOverlaps},
+    hal::flash::{
+        merge_write, write_buffered, Background, EraseBlock, EraseRegion, Geometry, MergeError,
+        MergeScratch, Progress, ProgressHook, ReadWrite, ReportProgress, CHUNK_SIZE,
     },
+    stm32pac::FLASH,
+    utilities::memory,
 };
 use core::ops::{Add, Sub};
 use nb::block;
 
 pub struct McuFlash {
     flash: FLASH,
+    progress: Option<ProgressHook<Address>>,
+    scratch: Option<&'static mut [u8]>,
 }
 
 #[derive(Copy, Clone, Debug)]
 pub enum Error {
     MemoryNotReachable,
     MisalignedAccess,
+    /// The write would set bits back to 1, so its sector must be erased, but
+    /// the rest of the sector doesn't fit the scratch buffer (see `MergeScratch`).
+    NeedsErase,
+}
+
+/// A write that can't be merged without an erase needs a larger scratch
+/// buffer, so it's reported as needing an erase.
+impl From<MergeError<Error>> for Error {
+    fn from(error: MergeError<Error>) -> Self {
+        match error {
+            MergeError::Flash(error) => error,
+            MergeError::ScratchTooSmall { .. } => Error::NeedsErase,
+            MergeError::OutOfBounds => Error::MemoryNotReachable,
+        }
+    }
 }
 
 #[derive(Default, Copy, Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
@@ -74,6 +91,10 @@ pub struct MemoryMap {
 ///From [section 3.5.1](../../../../../../../documentation/hardware/stm32f412_reference.pdf#page=62)
 const UNLOCK_KEYS: [u32; 2] = [0x45670123, 0xCDEF89AB];
 
+/// Scratch used when no buffer is set through `MergeScratch`. Enough to check
+/// writes against the memory they overwrite, but not to hold a sector.
+const DEFAULT_SCRATCH_SIZE: usize = 256;
+
 #[cfg(feature = "stm32f412")]
 const SECTOR_NUMBER: usize = 15;
 
@@ -98,18 +119,6 @@ const MEMORY_MAP: MemoryMap = MemoryMap {
     ],
 };
 
-const fn max_sector_size() -> usize {
-    let (mut index, mut size) = (0, 0usize);
-    loop {
-        let sector_size = MEMORY_MAP.sectors[index].size;
-        size = if sector_size > size { sector_size } else { size };
-        index += 1;
-        if index == SECTOR_NUMBER {
-            break size;
-        }
-    }
-}
-
 impl MemoryMap {
     // Verifies that the memory map is consecutive and well formed
     fn is_sound(&self) -> bool {
@@ -122,6 +131,17 @@ impl MemoryMap {
     }
 
     fn sectors() -> impl Iterator<Item = Sector> { MEMORY_MAP.sectors.iter().cloned() }
+
+    /// Sectors available to the application, as exposed through `range()`.
+    fn writable_sectors() -> impl Iterator<Item = Sector> {
+        Self::sectors().filter(Sector::is_writable)
+    }
+
+    /// Sectors holding any address in the `[start, end)` range.
+    fn sectors_overlapping(start: Address, end: Address) -> impl Iterator<Item = Sector> {
+        Self::sectors().filter(move |s| (s.start() < end) && (start < s.end()))
+    }
+
     pub const fn writable_start() -> Address {
         let mut i = 0;
         loop {
@@ -216,7 +236,7 @@ impl Sector {
 impl McuFlash {
     pub fn new(flash: FLASH) -> Result<Self, Error> {
         assert!(MEMORY_MAP.is_sound());
-        Ok(Self { flash })
+        Ok(Self { flash, progress: None, scratch: None })
     }
 
     /// Parallelism for 3v3 voltage from [table 7](../../../../../../../../documentation/hardware/stm32f412_reference.pdf#page=63)
@@ -247,6 +267,12 @@ impl McuFlash {
 
     fn is_busy(&self) -> bool { self.flash.sr.read().bsy().bit_is_set() }
 
+    fn report(&self, done: usize, total: Option<usize>, sector: &Sector) {
+        if let Some(hook) = self.progress {
+            hook(Progress { done, total, sector: sector.start() });
+        }
+    }
+
     fn write_bytes(
         &mut self,
         bytes: &[u8],
@@ -266,7 +292,7 @@ impl McuFlash {
             ])
         });
 
-        block!(self.unlock())?;
+        self.unlock()?;
         self.flash.cr.modify(|_, w| w.pg().set_bit());
         let base_address = address.0 as *mut u32;
         for (index, word) in words.enumerate() {
@@ -294,8 +320,12 @@ impl ReadWrite for McuFlash {
     // NOTE: This only erases the sections of the MCU flash that are writable
     // from the application's perspective. Not the reserved sector, system bytes, etc.
     fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        let total = MemoryMap::writable_sectors().map(|s| s.size).sum();
+        let mut done = 0;
         for sector in MEMORY_MAP.sectors.iter().filter(|s| s.is_writable()) {
             self.erase(sector)?;
+            done += sector.size;
+            self.report(done, Some(total), sector);
         }
         Ok(())
     }
@@ -315,28 +345,17 @@ impl ReadWrite for McuFlash {
             return Err(nb::Error::WouldBlock);
         }
 
-        for (block, sector, address) in MemoryMap::sectors().overlaps(bytes, address) {
-            let sector_data = &mut [0u8; max_sector_size()][0..sector.size];
-            let offset_into_sector = address.0.saturating_sub(sector.start().0) as usize;
-
-            block!(self.read(sector.start(), sector_data))?;
-            if block.is_subset_of(&sector_data[offset_into_sector..sector.size]) {
-                // No need to erase the sector, as we can just flip bits off
-                // (since our block is a bitwise subset of the sector)
-                block!(self.write_bytes(block, &sector, address))?;
-            } else {
-                // We have to erase and rewrite any saved data alongside the new block
-                block!(self.erase(&sector))?;
-                sector_data
-                    .iter_mut()
-                    .skip(offset_into_sector)
-                    .zip(block)
-                    .for_each(|(byte, input)| *byte = *input);
-                block!(self.write_bytes(sector_data, &sector, sector.location))?;
-            }
-        }
-
-        Ok(())
+        let mut fallback = [0u8; DEFAULT_SCRATCH_SIZE];
+        let (mut scratch, hook) = (self.scratch.take(), self.progress);
+        let result = merge_write(
+            self,
+            address,
+            bytes,
+            scratch.as_deref_mut().unwrap_or(&mut fallback),
+            hook,
+        );
+        self.scratch = scratch;
+        result.map_err(|error| nb::Error::Other(error.into()))
     }
 
     fn read(&mut self, address: Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
@@ -359,33 +378,87 @@ impl ReadWrite for McuFlash {
         address: Self::Address,
         blocks: I,
     ) -> Result<(), Self::Error> {
-        const TRANSFER_SIZE: usize = KB!(4);
-        assert!(TRANSFER_SIZE % N == 0);
-        let mut transfer_array = [0x00u8; TRANSFER_SIZE];
-        let mut memory_index = 0usize;
-
-        for block in blocks {
-            let slice = &mut transfer_array
-                [(memory_index % TRANSFER_SIZE)..((memory_index % TRANSFER_SIZE) + N)];
-            slice.clone_from_slice(&block);
-            memory_index += N;
-
-            if memory_index % TRANSFER_SIZE == 0 {
-                nb::block!(self.write(address + (memory_index - TRANSFER_SIZE), &transfer_array))?;
-                transfer_array.iter_mut().for_each(|b| *b = 0x00u8);
-            }
+        // Progress is reported for the whole transfer, rather than per write.
+        let hook = self.progress.take();
+        let result = write_buffered(self, address, blocks, &mut [0x00u8; CHUNK_SIZE], hook);
+        self.progress = hook;
+        result
+    }
+
+    fn label() -> &'static str { "stm32f4 flash (Internal)" }
+}
+
+impl EraseRegion for McuFlash {
+    fn erase_range(&mut self, start: Address, end: Address) -> nb::Result<(), Self::Error> {
+        let (writable_start, writable_end) = self.range();
+        if (start > end) || (start < writable_start) || (end > writable_end) {
+            return Err(nb::Error::Other(Error::MemoryNotReachable));
+        }
+
+        // Early yield if busy
+        if self.is_busy() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let total = MemoryMap::sectors_overlapping(start, end).map(|s| s.size).sum();
+        let mut done = 0;
+        for sector in MemoryMap::sectors_overlapping(start, end) {
+            block!(self.erase(&sector))?;
+            done += sector.size;
+            self.report(done, Some(total), &sector);
         }
-        let remainder = &transfer_array[0..(memory_index % TRANSFER_SIZE)];
-        nb::block!(self.write(address + (memory_index - remainder.len()), &remainder))?;
         Ok(())
     }
+}
 
-    fn label() -> &'static str { "stm32f4 flash (Internal)" }
+impl Geometry for McuFlash {
+    fn erase_block_count(&self) -> usize { MemoryMap::writable_sectors().count() }
+
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Address>> {
+        MemoryMap::writable_sectors()
+            .nth(index)
+            .map(|sector| EraseBlock { start: sector.start(), size: sector.size })
+    }
+
+    /// Writes are performed a word at a time.
+    fn write_alignment(&self) -> usize { 4 }
+}
+
+impl ReportProgress for McuFlash {
+    fn set_progress_hook(&mut self, hook: Option<ProgressHook<Address>>) { self.progress = hook; }
+}
+
+impl MergeScratch for McuFlash {
+    fn set_scratch_buffer(&mut self, scratch: Option<&'static mut [u8]>) { self.scratch = scratch; }
+}
+
+impl Background for McuFlash {
+    fn cycle_in_progress(&mut self) -> Result<bool, Error> { Ok(self.is_busy()) }
+
+    fn begin_erase(&mut self, block: EraseBlock<Address>) -> nb::Result<(), Error> {
+        let sector = MemoryMap::writable_sectors()
+            .find(|s| (s.start() == block.start) && (s.size == block.size))
+            .ok_or(Error::MemoryNotReachable)?;
+        self.erase(&sector)
+    }
+
+    /// Programs a single word.
+    fn begin_program(&mut self, address: Address, bytes: &[u8]) -> nb::Result<usize, Error> {
+        if (address.0 & 0b11 != 0) || (bytes.len() < 4) {
+            return Err(nb::Error::Other(Error::MisalignedAccess));
+        }
+        let sector = MemoryMap::writable_sectors()
+            .find(|s| memory::Region::contains(s, address))
+            .ok_or(Error::MemoryNotReachable)?;
+        self.write_bytes(&bytes[..4], &sector, address)?;
+        Ok(4)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::hal::doubles::flash as fake;
 
     #[test]
     fn ranges_overlap_sectors_correctly() {
@@ -415,10 +488,74 @@ mod test {
         assert_eq!(end, MEMORY_MAP.sectors[11].end());
     }
 
+    #[test]
+    fn finding_sectors_overlapping_a_range() {
+        let sectors: Vec<_> =
+            MemoryMap::sectors_overlapping(Address(0x0801_0000), Address(0x0802_0001)).collect();
+        assert_eq!(&sectors[..], &MEMORY_MAP.sectors[4..6]);
+
+        let sectors: Vec<_> =
+            MemoryMap::sectors_overlapping(Address(0x0801_FFFF), Address(0x0802_0000)).collect();
+        assert_eq!(&sectors[..], &MEMORY_MAP.sectors[4..5]);
+
+        assert_eq!(
+            MemoryMap::sectors_overlapping(Address(0x0802_0000), Address(0x0802_0000)).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn writable_sectors_cover_the_writable_range() {
+        let sectors: Vec<_> = MemoryMap::writable_sectors().collect();
+        assert_eq!(&sectors[..], &MEMORY_MAP.sectors[4..12]);
+        assert_eq!(sectors.first().unwrap().start(), MemoryMap::writable_start());
+        assert_eq!(sectors.last().unwrap().end(), MemoryMap::writable_end());
+    }
+
     #[test]
     fn ranges_are_correctly_marked_writable() {
         let (start, size) = (Address(0x0801_0008), 48usize);
         let range = Range(start, Address(start.0 + size as u32));
         assert!(range.is_writable());
     }
+
+    #[test]
+    fn writes_merge_partial_words_and_fail_before_erasing_without_scratch() {
+        // Given
+        let layout: Vec<_> = MemoryMap::writable_sectors().map(|s| (1, s.size)).collect();
+        let start = fake::Address(MemoryMap::writable_start().0);
+        let mut flash = fake::FakeFlash::with_layout(start, &layout, fake::ProgramMode::Strict);
+        flash.set_write_alignment(4);
+        let mut scratch = [0u8; DEFAULT_SCRATCH_SIZE];
+        let boundary = start + KB!(64);
+
+        // When
+        merge_write(&mut flash, boundary - 6, &[0x12; 11], &mut scratch, None).unwrap();
+        let rewrite = merge_write(&mut flash, boundary - 6, &[0xFF; 11], &mut scratch, None);
+
+        // Then
+        assert!(matches!(rewrite, Err(MergeError::ScratchTooSmall { .. })));
+        assert!(flash.erase_cycles().iter().all(|cycles| *cycles == 0));
+        let mut bytes = [0u8; 16];
+        flash.read(boundary - 8, &mut bytes).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0xFF, 0xFF, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0xFF,
+                0xFF, 0xFF
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_errors_map_to_driver_errors() {
+        let error = |merge| Error::from(merge);
+
+        assert!(matches!(error(MergeError::ScratchTooSmall { required: 1 }), Error::NeedsErase));
+        assert!(matches!(error(MergeError::OutOfBounds), Error::MemoryNotReachable));
+        assert!(matches!(
+            error(MergeError::Flash(Error::MisalignedAccess)),
+            Error::MisalignedAccess
+        ));
+    }
 }
@@ -457,6 +457,32 @@ macro_rules! hal_usart_impl {
                 }
             }
 
+            impl<PINS> serial::WriteByte for Serial<$USARTX, PINS> {
+                type Error = Error;
+
+                fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+                    let mut tx: Tx<$USARTX> = Tx {
+                        _usart: PhantomData,
+                    };
+                    tx.write_byte(byte)
+                }
+            }
+
+            impl serial::WriteByte for Tx<$USARTX> {
+                type Error = Error;
+
+                fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+                    // NOTE(Safety) atomic read with no side effects
+                    if ! unsafe { (*$USARTX::ptr()).sr.read().txe().bit_is_set() } {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    // NOTE(Safety) atomic write to stateless register
+                    // NOTE(write_volatile) 8-bit write that's not possible through the svd2rust API
+                    unsafe { ptr::write_volatile(&(*$USARTX::ptr()).dr as *const _ as *mut _, byte) }
+                    Ok(())
+                }
+            }
+
             impl serial::Write for Tx<$USARTX> {
                 type Error = Error;
 
@@ -468,12 +494,7 @@ macro_rules! hal_usart_impl {
                 }
 
                 fn write_char(&mut self, c: char) -> Result<(), Self::Error> {
-                    // NOTE(Safety) atomic read with no side effects
-                    while ! unsafe { (*$USARTX::ptr()).sr.read().txe().bit_is_set() } {}
-                    // NOTE(Safety) atomic write to stateless register
-                    // NOTE(write_volatile) 8-bit write that's not possible through the svd2rust API
-                    unsafe { ptr::write_volatile(&(*$USARTX::ptr()).dr as *const _ as *mut _, c as u8) }
-                    Ok(())
+                    nb::block!(serial::WriteByte::write_byte(self, c as u8))
                 }
             }
         )+
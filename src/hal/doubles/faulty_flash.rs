@@ -0,0 +1,360 @@
+//! Fault injection wrapper for any flash, to exercise error handling and
+//! power loss recovery on the host.
+//!
+//! Faults are scheduled relative to the operations performed after they are
+//! injected. A simulated power loss leaves the interrupted operation torn,
+//! and every following access fails until power is restored.
+use crate::hal::flash::{self, EraseBlock};
+
+/// State of the bytes an interrupted operation didn't get to finish.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tear {
+    /// Bytes keep their previous contents.
+    Untouched,
+    /// Bytes are left somewhere between their previous and intended contents,
+    /// following a pseudorandom sequence generated from the seed.
+    Randomised { seed: u32 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The next `operations` accesses yield `WouldBlock`.
+    Busy { operations: usize },
+    /// The `write`th write from now (starting at 0) fails without touching the memory.
+    WriteError { write: usize },
+    /// Power is lost during the `write`th write from now, after programming
+    /// its first `programmed` bytes.
+    PowerLossOnWrite { write: usize, programmed: usize, tear: Tear },
+    /// Power is lost during the `erase`th erase from now.
+    PowerLossOnErase { erase: usize, tear: Tear },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultyFlashError<E> {
+    /// Error raised by the wrapped flash.
+    Flash(E),
+    /// Error raised by a `Fault::WriteError`.
+    Injected,
+    /// Power is lost, and must be restored before accessing the flash again.
+    PowerLost,
+}
+
+pub struct FaultyFlash<F: flash::ReadWrite> {
+    pub inner: F,
+    busy: usize,
+    writes: usize,
+    erases: usize,
+    faults: Vec<Fault>,
+    powered: bool,
+}
+
+impl<F: flash::ReadWrite> FaultyFlash<F> {
+    pub fn new(inner: F) -> Self {
+        Self { inner, busy: 0, writes: 0, erases: 0, faults: Vec::new(), powered: true }
+    }
+
+    pub fn inject(&mut self, fault: Fault) {
+        let fault = match fault {
+            Fault::Busy { operations } => {
+                self.busy += operations;
+                return;
+            }
+            Fault::WriteError { write } => Fault::WriteError { write: self.writes + write },
+            Fault::PowerLossOnWrite { write, programmed, tear } => {
+                Fault::PowerLossOnWrite { write: self.writes + write, programmed, tear }
+            }
+            Fault::PowerLossOnErase { erase, tear } => {
+                Fault::PowerLossOnErase { erase: self.erases + erase, tear }
+            }
+        };
+        self.faults.push(fault);
+    }
+
+    pub fn restore_power(&mut self) { self.powered = true; }
+    pub fn is_powered(&self) -> bool { self.powered }
+    pub fn into_inner(self) -> F { self.inner }
+
+    /// Common checks before any access, consuming a pending `Busy` fault.
+    fn access(&mut self) -> nb::Result<(), FaultyFlashError<F::Error>> {
+        if !self.powered {
+            Err(nb::Error::Other(FaultyFlashError::PowerLost))
+        } else if self.busy > 0 {
+            self.busy -= 1;
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn take_fault(&mut self, matches: impl Fn(&Fault) -> bool) -> Option<Fault> {
+        let index = self.faults.iter().position(matches)?;
+        Some(self.faults.remove(index))
+    }
+
+    fn take_erase_fault(&mut self) -> Option<Tear> {
+        let erase = self.erases;
+        self.erases += 1;
+        match self
+            .take_fault(|f| matches!(f, Fault::PowerLossOnErase { erase: e, .. } if *e == erase))
+        {
+            Some(Fault::PowerLossOnErase { tear, .. }) => Some(tear),
+            _ => None,
+        }
+    }
+
+    /// Leaves a block partially erased after an interrupted erase.
+    fn tear_erase(
+        &mut self,
+        start: F::Address,
+        previous: &[u8],
+        tear: Tear,
+    ) -> Result<(), FaultyFlashError<F::Error>> {
+        if let Tear::Randomised { seed } = tear {
+            // Erasing only sets bits, so the torn contents sit anywhere between
+            // the previous contents and all ones.
+            let torn: Vec<u8> =
+                previous.iter().zip(Noise(seed.max(1))).map(|(old, noise)| old | noise).collect();
+            nb::block!(self.inner.write(start, &torn)).map_err(FaultyFlashError::Flash)?;
+        } else {
+            nb::block!(self.inner.write(start, previous)).map_err(FaultyFlashError::Flash)?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: flash::ReadWrite> flash::ReadWrite for FaultyFlash<F> {
+    type Error = FaultyFlashError<F::Error>;
+    type Address = F::Address;
+
+    fn label() -> &'static str { "Faulty Flash" }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        self.access()?;
+        self.inner.read(address, bytes).map_err(|e| e.map(FaultyFlashError::Flash))
+    }
+
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        self.access()?;
+        let write = self.writes;
+        self.writes += 1;
+        let fault = self.take_fault(|f| match f {
+            Fault::WriteError { write: w } | Fault::PowerLossOnWrite { write: w, .. } => {
+                *w == write
+            }
+            _ => false,
+        });
+
+        let (programmed, tear) = match fault {
+            Some(Fault::WriteError { .. }) => {
+                return Err(nb::Error::Other(FaultyFlashError::Injected))
+            }
+            Some(Fault::PowerLossOnWrite { programmed, tear, .. }) => {
+                (programmed.min(bytes.len()), tear)
+            }
+            _ => {
+                return self.inner.write(address, bytes).map_err(|e| e.map(FaultyFlashError::Flash))
+            }
+        };
+
+        self.powered = false;
+        let (done, pending) = bytes.split_at(programmed);
+        nb::block!(self.inner.write(address, done)).map_err(FaultyFlashError::Flash)?;
+        if let Tear::Randomised { seed } = tear {
+            // Programming only clears bits, so the torn bytes sit anywhere
+            // between their previous and intended contents.
+            let pending_address = address + programmed;
+            let mut torn = vec![0u8; pending.len()];
+            nb::block!(self.inner.read(pending_address, &mut torn))
+                .map_err(FaultyFlashError::Flash)?;
+            torn.iter_mut()
+                .zip(pending)
+                .zip(Noise(seed.max(1)))
+                .for_each(|((old, new), noise)| *old &= new | noise);
+            nb::block!(self.inner.write(pending_address, &torn))
+                .map_err(FaultyFlashError::Flash)?;
+        }
+        Err(nb::Error::Other(FaultyFlashError::PowerLost))
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) { self.inner.range() }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        self.access()?;
+        match self.take_erase_fault() {
+            None => self.inner.erase().map_err(|e| e.map(FaultyFlashError::Flash)),
+            Some(tear) => {
+                self.powered = false;
+                let (start, end) = self.inner.range();
+                let mut previous = vec![0u8; end - start];
+                nb::block!(self.inner.read(start, &mut previous))
+                    .map_err(FaultyFlashError::Flash)?;
+                nb::block!(self.inner.erase()).map_err(FaultyFlashError::Flash)?;
+                self.tear_erase(start, &previous, tear)?;
+                Err(nb::Error::Other(FaultyFlashError::PowerLost))
+            }
+        }
+    }
+}
+
+impl<F: flash::EraseRegion + flash::Geometry> flash::EraseRegion for FaultyFlash<F> {
+    fn erase_range(
+        &mut self,
+        start: Self::Address,
+        end: Self::Address,
+    ) -> nb::Result<(), Self::Error> {
+        self.access()?;
+        let tear = match self.take_erase_fault() {
+            None => {
+                return self
+                    .inner
+                    .erase_range(start, end)
+                    .map_err(|e| e.map(FaultyFlashError::Flash))
+            }
+            Some(tear) => tear,
+        };
+
+        self.powered = false;
+        let blocks: Vec<_> =
+            self.inner.erase_blocks().filter(|b| (b.start < end) && (start < b.end())).collect();
+        let mut previous = Vec::new();
+        for block in &blocks {
+            let mut bytes = vec![0u8; block.size];
+            nb::block!(self.inner.read(block.start, &mut bytes))
+                .map_err(FaultyFlashError::Flash)?;
+            previous.push(bytes);
+        }
+        nb::block!(self.inner.erase_range(start, end)).map_err(FaultyFlashError::Flash)?;
+        for (block, bytes) in blocks.iter().zip(previous) {
+            self.tear_erase(block.start, &bytes, tear)?;
+        }
+        Err(nb::Error::Other(FaultyFlashError::PowerLost))
+    }
+}
+
+impl<F: flash::Geometry> flash::Geometry for FaultyFlash<F> {
+    fn erase_block_count(&self) -> usize { self.inner.erase_block_count() }
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Self::Address>> {
+        self.inner.erase_block(index)
+    }
+    fn write_alignment(&self) -> usize { self.inner.write_alignment() }
+    fn erased_value(&self) -> u8 { self.inner.erased_value() }
+}
+
+/// Xorshift sequence, reproducible from its (non zero) seed.
+struct Noise(u32);
+
+impl Iterator for Noise {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        Some(self.0 as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::{
+        doubles::flash::{self, Address, FakeFlash, FakeFlashError},
+        flash::{EraseRegion, ReadWrite},
+    };
+
+    fn faulty_flash() -> FaultyFlash<FakeFlash> {
+        let inner = FakeFlash::uniform(4, 16);
+        FaultyFlash::new(inner)
+    }
+
+    fn contents(flash: &mut FaultyFlash<FakeFlash>) -> Vec<u8> {
+        flash::contents(&mut flash.inner, Address(0), 64)
+    }
+
+    #[test]
+    fn busy_faults_yield_before_succeeding() {
+        let mut flash = faulty_flash();
+        flash.inject(Fault::Busy { operations: 2 });
+
+        assert_eq!(flash.write(Address(0), &[0x00]), Err(nb::Error::WouldBlock));
+        assert_eq!(flash.read(Address(0), &mut [0u8]), Err(nb::Error::WouldBlock));
+        assert_eq!(nb::block!(flash.write(Address(0), &[0x00])), Ok(()));
+    }
+
+    #[test]
+    fn write_errors_fail_only_the_scheduled_write() {
+        // Given
+        let mut flash = faulty_flash();
+        flash.inject(Fault::WriteError { write: 1 });
+
+        // When
+        let results: Vec<_> = (0..3).map(|i| flash.write(Address(i), &[0x00])).collect();
+
+        // Then
+        assert_eq!(
+            results,
+            vec![Ok(()), Err(nb::Error::Other(FaultyFlashError::Injected)), Ok(())]
+        );
+        assert_eq!(&contents(&mut flash)[..3], &[0x00, 0xFF, 0x00]);
+    }
+
+    #[test]
+    fn power_loss_during_a_write_leaves_it_partially_programmed() {
+        // Given
+        let mut flash = faulty_flash();
+        let tear = Tear::Untouched;
+        flash.inject(Fault::PowerLossOnWrite { write: 0, programmed: 3, tear });
+
+        // When
+        let result = flash.write(Address(0), &[0x00; 8]);
+
+        // Then
+        assert_eq!(result, Err(nb::Error::Other(FaultyFlashError::PowerLost)));
+        assert_eq!(
+            flash.read(Address(0), &mut [0u8]),
+            Err(nb::Error::Other(FaultyFlashError::PowerLost))
+        );
+        flash.restore_power();
+        assert_eq!(&contents(&mut flash)[..9], &[0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn randomised_torn_writes_only_clear_bits_towards_the_intended_value() {
+        let mut flash = faulty_flash();
+        let tear = Tear::Randomised { seed: 42 };
+        flash.inject(Fault::PowerLossOnWrite { write: 0, programmed: 0, tear });
+
+        flash.write(Address(0), &[0x0F; 16]).unwrap_err();
+
+        let torn = &contents(&mut flash)[..16];
+        assert!(torn.iter().all(|b| b & 0x0F == 0x0F));
+        assert!(torn.iter().any(|b| *b != 0xFF));
+    }
+
+    #[test]
+    fn power_loss_during_an_erase_leaves_sectors_partially_erased() {
+        // Given
+        let mut flash = faulty_flash();
+        flash.write(Address(0), &[0x00; 64]).unwrap();
+        flash.inject(Fault::PowerLossOnErase { erase: 0, tear: Tear::Randomised { seed: 7 } });
+
+        // When
+        let result = flash.erase_range(Address(16), Address(17));
+
+        // Then
+        assert_eq!(result, Err(nb::Error::Other(FaultyFlashError::PowerLost)));
+        flash.restore_power();
+        let bytes = contents(&mut flash);
+        assert!(bytes[..16].iter().chain(&bytes[32..]).all(|b| *b == 0x00));
+        assert!(bytes[16..32].iter().any(|b| (*b != 0x00) && (*b != 0xFF)));
+    }
+
+    #[test]
+    fn errors_from_the_wrapped_flash_are_forwarded() {
+        let mut flash = faulty_flash();
+        assert_eq!(
+            flash.write(Address(64), &[0x00]),
+            Err(nb::Error::Other(FaultyFlashError::Flash(FakeFlashError::OutOfRange)))
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! NOR flash simulator persisted to a file, for host side simulations.
+//!
+//! The file holds a raw image of the memory, so it can be inspected or
+//! pre-seeded with standard tools. It follows the same programming and
+//! erase rules as `FakeFlash`.
+use super::flash::{program, sectors_from_layout, Address, ProgramMode, ERASED};
+use crate::hal::flash::{self, EraseBlock};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileFlashError {
+    /// The access falls (at least partly) outside the simulated memory.
+    OutOfRange,
+    /// A write tried to set a bit that was cleared, at this address.
+    ProgramWithoutErase(Address),
+    /// The backing file could not be accessed.
+    Io(io::ErrorKind),
+}
+
+pub struct FileFlash {
+    file: File,
+    base: Address,
+    size: usize,
+    sectors: Vec<EraseBlock<Address>>,
+    mode: ProgramMode,
+}
+
+impl FileFlash {
+    /// Opens the image at `path`, laid out in consecutive groups of
+    /// `(count, size)` sectors. A missing image is created erased, and a
+    /// short one is padded with erased bytes.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        base: Address,
+        layout: &[(usize, usize)],
+        mode: ProgramMode,
+    ) -> io::Result<Self> {
+        let sectors = sectors_from_layout(base, layout);
+        let size = sectors.last().map_or(base, EraseBlock::end) - base;
+        let mut file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+        let length = file.metadata()?.len() as usize;
+        if length > size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Image exceeds flash size"));
+        }
+        file.seek(SeekFrom::Start(length as u64))?;
+        file.write_all(&vec![ERASED; size - length])?;
+        Ok(Self { file, base, size, sectors, mode })
+    }
+
+    fn offset_of(&self, address: Address, size: usize) -> Result<usize, FileFlashError> {
+        if (address < self.base) || (address + size > self.base + self.size) {
+            Err(FileFlashError::OutOfRange)
+        } else {
+            Ok(address - self.base)
+        }
+    }
+
+    fn read_at(&mut self, offset: usize, bytes: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.read_exact(bytes)
+    }
+
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(bytes)?;
+        self.file.flush()
+    }
+
+    fn erase_sector(&mut self, block: EraseBlock<Address>) -> Result<(), FileFlashError> {
+        Ok(self.write_at(block.start - self.base, &vec![ERASED; block.size])?)
+    }
+}
+
+impl flash::ReadWrite for FileFlash {
+    type Error = FileFlashError;
+    type Address = Address;
+
+    fn label() -> &'static str { "File Flash" }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        let offset = self.offset_of(address, bytes.len())?;
+        self.read_at(offset, bytes).map_err(FileFlashError::from)?;
+        Ok(())
+    }
+
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        let offset = self.offset_of(address, bytes.len())?;
+        let mut memory = vec![0u8; bytes.len()];
+        self.read_at(offset, &mut memory).map_err(FileFlashError::from)?;
+        program(&mut memory, bytes, self.mode)
+            .map_err(|index| FileFlashError::ProgramWithoutErase(address + index))?;
+        self.write_at(offset, &memory).map_err(FileFlashError::from)?;
+        Ok(())
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) { (self.base, self.base + self.size) }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        self.write_at(0, &vec![ERASED; self.size]).map_err(FileFlashError::from)?;
+        Ok(())
+    }
+}
+
+impl flash::EraseRegion for FileFlash {
+    fn erase_range(&mut self, start: Address, end: Address) -> nb::Result<(), Self::Error> {
+        if start > end {
+            return Err(nb::Error::Other(FileFlashError::OutOfRange));
+        }
+        self.offset_of(start, end - start)?;
+        let overlapping: Vec<_> =
+            self.sectors.iter().filter(|s| (s.start < end) && (start < s.end())).copied().collect();
+        for sector in overlapping {
+            self.erase_sector(sector)?;
+        }
+        Ok(())
+    }
+}
+
+impl flash::Geometry for FileFlash {
+    fn erase_block_count(&self) -> usize { self.sectors.len() }
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Address>> {
+        self.sectors.get(index).copied()
+    }
+    fn write_alignment(&self) -> usize { 1 }
+}
+
+impl From<io::Error> for FileFlashError {
+    fn from(error: io::Error) -> Self { FileFlashError::Io(error.kind()) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::flash::{EraseRegion, ReadWrite};
+    use std::{env, fs, path::PathBuf};
+
+    const LAYOUT: &[(usize, usize)] = &[(4, 16)];
+
+    fn image_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("blue_hal_{}_{}.bin", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn file_flash_contents_persist_across_instances() {
+        // Given
+        let path = image_path("persist");
+        let mut flash = FileFlash::open(&path, Address(0), LAYOUT, ProgramMode::Strict).unwrap();
+        flash.write(Address(20), &[0xAA, 0x55]).unwrap();
+        drop(flash);
+
+        // When
+        let mut flash = FileFlash::open(&path, Address(0), LAYOUT, ProgramMode::Strict).unwrap();
+        let mut bytes = [0u8; 4];
+        flash.read(Address(19), &mut bytes).unwrap();
+
+        // Then
+        assert_eq!(bytes, [0xFF, 0xAA, 0x55, 0xFF]);
+        let image = fs::read(&path).unwrap();
+        assert_eq!(image.len(), 64);
+        assert_eq!(&image[20..22], &[0xAA, 0x55]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_flash_pads_seeded_images_and_rejects_oversized_ones() {
+        let path = image_path("seeded");
+        fs::write(&path, [0x12, 0x34]).unwrap();
+
+        let mut flash = FileFlash::open(&path, Address(0), LAYOUT, ProgramMode::Strict).unwrap();
+        let mut bytes = [0u8; 3];
+        flash.read(Address(0), &mut bytes).unwrap();
+        assert_eq!(bytes, [0x12, 0x34, 0xFF]);
+
+        let oversized = FileFlash::open(&path, Address(0), &[(1, 16)], ProgramMode::Strict);
+        assert_eq!(oversized.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_flash_follows_nor_rules() {
+        let path = image_path("nor");
+        let mut flash = FileFlash::open(&path, Address(0), LAYOUT, ProgramMode::Strict).unwrap();
+        flash.write(Address(0), &[0x00; 64]).unwrap();
+
+        assert_eq!(
+            flash.write(Address(1), &[0x01]),
+            Err(nb::Error::Other(FileFlashError::ProgramWithoutErase(Address(1))))
+        );
+        flash.erase_range(Address(17), Address(18)).unwrap();
+
+        let image = fs::read(&path).unwrap();
+        assert!(image[..16].iter().chain(&image[32..]).all(|b| *b == 0x00));
+        assert!(image[16..32].iter().all(|b| *b == 0xFF));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,65 +1,262 @@
-use super::error::FakeError;
-use crate::hal::flash;
-use std::{
-    cmp::max,
-    ops::{Add, Sub},
-};
+//! NOR flash simulator, for testing flash-generic code off target.
+//!
+//! Like real NOR parts, the simulated memory starts erased (all 0xFF),
+//! programming can only clear bits, and only whole sectors can be erased.
+use crate::hal::flash::{self, EraseBlock};
+use std::ops::{Add, Sub};
+
+/// Sector size of the default layout.
+pub const SECTOR_SIZE: usize = KB!(4);
+
+/// Size of the default layout.
+pub const DEFAULT_SIZE: usize = MB!(16);
+
+/// Bytes programmed per background cycle, like a NOR page.
+pub const PAGE_SIZE: usize = 256;
+
+pub(super) const ERASED: u8 = 0xFF;
+
+/// How the simulator reacts to a write that would need to set a bit back to 1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProgramMode {
+    /// Fail the whole write, leaving the memory untouched.
+    Strict,
+    /// Program anyway, AND-ing the new data with the old like the hardware would.
+    BitwiseAnd,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FakeFlashError {
+    /// The access falls (at least partly) outside the simulated memory.
+    OutOfRange,
+    /// A write tried to set a bit that was cleared, at this address.
+    ProgramWithoutErase(Address),
+    /// A write started or ended off a word boundary, at this address.
+    MisalignedAccess(Address),
+}
+
+/// Number of operations performed on a `FakeFlash` since its creation.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub reads: usize,
+    pub writes: usize,
+    pub erases: usize,
+}
 
 pub struct FakeFlash {
     base: Address,
-    length: usize,
     data: Vec<u8>,
-}
-
-impl FakeFlash {
-    pub fn new(base: Address) -> FakeFlash { FakeFlash { base, data: Vec::new(), length: MB!(16) } }
+    sectors: Vec<EraseBlock<Address>>,
+    erase_cycles: Vec<u32>,
+    mode: ProgramMode,
+    alignment: usize,
+    busy_polls: usize,
+    busy: usize,
+    pub counters: Counters,
 }
 
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, PartialEq, Eq)]
 pub struct Address(pub u32);
 
-impl flash::ReadWrite for FakeFlash {
-    type Error = FakeError;
-    type Address = Address;
+impl FakeFlash {
+    /// 16MB of uniform 4KB sectors, rejecting writes that can't be done without an erase.
+    pub fn new(base: Address) -> FakeFlash {
+        Self::with_layout(base, &[(DEFAULT_SIZE / SECTOR_SIZE, SECTOR_SIZE)], ProgramMode::Strict)
+    }
 
-    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
-        if address < self.base {
-            Err(nb::Error::Other(FakeError))
+    /// Builds a flash from consecutive groups of `(count, size)` sectors,
+    /// e.g. `&[(4, KB!(16)), (1, KB!(64)), (7, KB!(128))]` for a stm32f412.
+    pub fn with_layout(base: Address, layout: &[(usize, usize)], mode: ProgramMode) -> FakeFlash {
+        let sectors = sectors_from_layout(base, layout);
+        let end = sectors.last().map_or(base, EraseBlock::end);
+        FakeFlash {
+            base,
+            data: vec![ERASED; end - base],
+            erase_cycles: vec![0; sectors.len()],
+            sectors,
+            mode,
+            alignment: 1,
+            busy_polls: 0,
+            busy: 0,
+            counters: Counters::default(),
+        }
+    }
+
+    /// `count` sectors of `size` bytes from address 0, rejecting writes that
+    /// can't be done without an erase.
+    pub fn uniform(count: usize, size: usize) -> FakeFlash {
+        Self::with_layout(Address(0), &[(count, size)], ProgramMode::Strict)
+    }
+
+    /// Number of times each sector has been erased, in address order.
+    pub fn erase_cycles(&self) -> &[u32] { &self.erase_cycles }
+
+    /// Number of times `cycle_in_progress` reports a background cycle as running after
+    /// it starts. Cycles take effect immediately regardless.
+    pub fn set_busy_polls(&mut self, polls: usize) { self.busy_polls = polls; }
+
+    /// Requires the address and length of every write to be a multiple of
+    /// `alignment` bytes, like a part programmed a word at a time.
+    pub fn set_write_alignment(&mut self, alignment: usize) { self.alignment = alignment; }
+
+    fn check_alignment(&self, address: Address, size: usize) -> Result<(), FakeFlashError> {
+        let misaligned = |offset: usize| !offset.is_multiple_of(self.alignment);
+        if misaligned(address - self.base) {
+            Err(FakeFlashError::MisalignedAccess(address))
+        } else if misaligned(size) {
+            Err(FakeFlashError::MisalignedAccess(address + size))
         } else {
-            self.data.iter().skip(address - self.base).zip(bytes).for_each(|(i, o)| *o = *i);
             Ok(())
         }
     }
 
-    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
-        if address < self.base {
-            Err(nb::Error::Other(FakeError))
+    fn begin_cycle(&mut self) -> nb::Result<(), FakeFlashError> {
+        if self.busy > 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.busy = self.busy_polls;
+        Ok(())
+    }
+
+    fn end(&self) -> Address { self.base + self.data.len() }
+
+    fn offset_of(&self, address: Address, size: usize) -> Result<usize, FakeFlashError> {
+        if (address < self.base) || (address + size > self.end()) {
+            Err(FakeFlashError::OutOfRange)
         } else {
-            let offset = address - self.base;
-            self.data.resize_with(max(self.data.len(), offset + bytes.len()), Default::default);
-            self.data.iter_mut().skip(offset).zip(bytes).for_each(|(o, i)| *o = *i);
-            Ok(())
+            Ok(address - self.base)
         }
     }
 
-    fn range(&self) -> (Self::Address, Self::Address) { (self.base, self.base + self.length) }
+    fn erase_sector(&mut self, index: usize) {
+        let sector = self.sectors[index];
+        let offset = sector.start - self.base;
+        self.data[offset..offset + sector.size].iter_mut().for_each(|b| *b = ERASED);
+        self.erase_cycles[index] += 1;
+    }
+}
 
-    fn erase(&mut self) -> nb::Result<(), Self::Error> {
-        self.data.clear();
+/// Reads `size` bytes of any flash from an address onwards, to check results.
+pub fn contents<F: flash::ReadWrite>(flash: &mut F, address: F::Address, size: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; size];
+    nb::block!(flash.read(address, &mut bytes)).map_err(|_| ()).unwrap();
+    bytes
+}
+
+/// Writes `size` copies of `value` to any flash from an address onwards.
+pub fn fill<F: flash::ReadWrite>(flash: &mut F, address: F::Address, size: usize, value: u8) {
+    nb::block!(flash.write(address, &vec![value; size])).map_err(|_| ()).unwrap();
+}
+
+/// Expands consecutive groups of `(count, size)` sectors starting at `base`.
+pub(super) fn sectors_from_layout(
+    base: Address,
+    layout: &[(usize, usize)],
+) -> Vec<EraseBlock<Address>> {
+    let sizes = layout.iter().flat_map(|(count, size)| (0..*count).map(move |_| *size));
+    let mut sectors = Vec::new();
+    let mut start = base;
+    for size in sizes {
+        sectors.push(EraseBlock { start, size });
+        start = start + size;
+    }
+    sectors
+}
+
+/// Programs `bytes` over `memory`, returning the offset of the first bit that
+/// would have to be set if that's not allowed by the mode.
+pub(super) fn program(memory: &mut [u8], bytes: &[u8], mode: ProgramMode) -> Result<(), usize> {
+    if mode == ProgramMode::Strict {
+        if let Some(index) = memory.iter().zip(bytes).position(|(old, new)| new & !old != 0) {
+            return Err(index);
+        }
+    }
+    memory.iter_mut().zip(bytes).for_each(|(old, new)| *old &= new);
+    Ok(())
+}
+
+impl flash::ReadWrite for FakeFlash {
+    type Error = FakeFlashError;
+    type Address = Address;
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        let offset = self.offset_of(address, bytes.len())?;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        self.counters.reads += 1;
         Ok(())
     }
 
-    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
-        &mut self,
-        _address: Self::Address,
-        _blocks: I,
-    ) -> Result<(), Self::Error> {
-        todo!()
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        let offset = self.offset_of(address, bytes.len())?;
+        self.check_alignment(address, bytes.len())?;
+        program(&mut self.data[offset..offset + bytes.len()], bytes, self.mode)
+            .map_err(|index| FakeFlashError::ProgramWithoutErase(address + index))?;
+        self.counters.writes += 1;
+        Ok(())
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) { (self.base, self.end()) }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        (0..self.sectors.len()).for_each(|index| self.erase_sector(index));
+        self.counters.erases += 1;
+        Ok(())
     }
 
     fn label() -> &'static str { "Fake Flash" }
 }
 
+impl flash::EraseRegion for FakeFlash {
+    fn erase_range(&mut self, start: Address, end: Address) -> nb::Result<(), Self::Error> {
+        if start > end {
+            return Err(nb::Error::Other(FakeFlashError::OutOfRange));
+        }
+        self.offset_of(start, end - start)?;
+        let overlapping: Vec<usize> = (0..self.sectors.len())
+            .filter(|i| (self.sectors[*i].start < end) && (start < self.sectors[*i].end()))
+            .collect();
+        overlapping.into_iter().for_each(|index| self.erase_sector(index));
+        self.counters.erases += 1;
+        Ok(())
+    }
+}
+
+impl flash::Geometry for FakeFlash {
+    fn erase_block_count(&self) -> usize { self.sectors.len() }
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Address>> {
+        self.sectors.get(index).copied()
+    }
+    fn write_alignment(&self) -> usize { self.alignment }
+}
+
+impl flash::Background for FakeFlash {
+    fn cycle_in_progress(&mut self) -> Result<bool, Self::Error> {
+        let busy = self.busy > 0;
+        self.busy = self.busy.saturating_sub(1);
+        Ok(busy)
+    }
+
+    fn begin_erase(&mut self, block: EraseBlock<Address>) -> nb::Result<(), Self::Error> {
+        let index =
+            self.sectors.iter().position(|s| *s == block).ok_or(FakeFlashError::OutOfRange)?;
+        self.begin_cycle()?;
+        self.erase_sector(index);
+        self.counters.erases += 1;
+        Ok(())
+    }
+
+    fn begin_program(&mut self, address: Address, bytes: &[u8]) -> nb::Result<usize, Self::Error> {
+        let offset = self.offset_of(address, bytes.len())?;
+        let size = bytes.len().min(PAGE_SIZE - (offset % PAGE_SIZE));
+        self.check_alignment(address, size)?;
+        self.begin_cycle()?;
+        program(&mut self.data[offset..offset + size], &bytes[..size], self.mode)
+            .map_err(|index| FakeFlashError::ProgramWithoutErase(address + index))?;
+        self.counters.writes += 1;
+        Ok(size)
+    }
+}
+
 impl Add<usize> for Address {
     type Output = Address;
     fn add(self, rhs: usize) -> Self::Output { Address(self.0 + rhs as u32) }
@@ -78,3 +275,91 @@ impl Sub<Address> for Address {
 impl From<Address> for usize {
     fn from(address: Address) -> Self { address.0 as usize }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::flash::{EraseRegion, Geometry, ReadWrite};
+
+    fn small_flash(mode: ProgramMode) -> FakeFlash {
+        FakeFlash::with_layout(Address(0x100), &[(2, 16), (1, 32)], mode)
+    }
+
+    #[test]
+    fn fake_flash_starts_erased_with_the_requested_layout() {
+        let mut flash = small_flash(ProgramMode::Strict);
+        let mut bytes = [0u8; 64];
+
+        flash.read(Address(0x100), &mut bytes).unwrap();
+
+        assert!(bytes.iter().all(|b| *b == 0xFF));
+        assert_eq!(flash.range(), (Address(0x100), Address(0x140)));
+        let sizes: Vec<_> = flash.erase_blocks().map(|b| b.size).collect();
+        assert_eq!(sizes, vec![16, 16, 32]);
+    }
+
+    #[test]
+    fn accesses_outside_the_fake_flash_fail() {
+        let mut flash = small_flash(ProgramMode::Strict);
+        let mut bytes = [0u8; 2];
+
+        assert_eq!(
+            flash.read(Address(0xFF), &mut bytes),
+            Err(nb::Error::Other(FakeFlashError::OutOfRange))
+        );
+        assert_eq!(
+            flash.write(Address(0x13F), &bytes),
+            Err(nb::Error::Other(FakeFlashError::OutOfRange))
+        );
+        assert_eq!(
+            flash.erase_range(Address(0x100), Address(0x141)),
+            Err(nb::Error::Other(FakeFlashError::OutOfRange))
+        );
+    }
+
+    #[test]
+    fn strict_fake_flash_rejects_setting_bits() {
+        // Given
+        let mut flash = small_flash(ProgramMode::Strict);
+        flash.write(Address(0x100), &[0xF0, 0x0F]).unwrap();
+
+        // When
+        let result = flash.write(Address(0x100), &[0x30, 0x1F]);
+
+        // Then
+        assert_eq!(
+            result,
+            Err(nb::Error::Other(FakeFlashError::ProgramWithoutErase(Address(0x101))))
+        );
+        let mut bytes = [0u8; 2];
+        flash.read(Address(0x100), &mut bytes).unwrap();
+        assert_eq!(bytes, [0xF0, 0x0F]);
+    }
+
+    #[test]
+    fn bitwise_and_fake_flash_programs_like_hardware() {
+        let mut flash = small_flash(ProgramMode::BitwiseAnd);
+        flash.write(Address(0x100), &[0xF0, 0x0F]).unwrap();
+
+        flash.write(Address(0x100), &[0x3C, 0x3C]).unwrap();
+
+        let mut bytes = [0u8; 2];
+        flash.read(Address(0x100), &mut bytes).unwrap();
+        assert_eq!(bytes, [0x30, 0x0C]);
+    }
+
+    #[test]
+    fn fake_flash_counts_operations_and_erase_cycles() {
+        // Given
+        let mut flash = small_flash(ProgramMode::Strict);
+        flash.write_from_blocks(Address(0x100), [[0u8; 8]; 4].iter().copied()).unwrap();
+
+        // When
+        flash.erase_range(Address(0x10F), Address(0x111)).unwrap();
+        flash.erase().unwrap();
+
+        // Then
+        assert_eq!(flash.counters, Counters { reads: 0, writes: 4, erases: 2 });
+        assert_eq!(flash.erase_cycles(), &[2, 2, 1]);
+    }
+}
@@ -4,4 +4,6 @@ pub mod spi;
 pub mod time;
 pub mod serial;
 pub mod flash;
+pub mod faulty_flash;
+pub mod file_flash;
 pub mod error;
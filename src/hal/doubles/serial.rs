@@ -1,4 +1,5 @@
 use crate::hal::{serial, time};
+use std::collections::VecDeque;
 
 #[derive(Copy, Clone, Debug)]
 pub struct SerialStubError;
@@ -9,6 +10,11 @@ impl serial::Write for SerialStub {
     fn write_str(&mut self, _s: &str) -> Result<(), Self::Error> { Ok(()) }
 }
 
+impl serial::WriteByte for SerialStub {
+    type Error = SerialStubError;
+    fn write_byte(&mut self, _byte: u8) -> nb::Result<(), Self::Error> { Ok(()) }
+}
+
 impl serial::Read for SerialStub {
     type Error = SerialStubError;
     fn read(&mut self) -> nb::Result<u8, Self::Error> { Ok(0) }
@@ -21,3 +27,55 @@ impl serial::TimeoutRead for SerialStub {
         Ok(0)
     }
 }
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScriptedSerialTimeout;
+
+/// Serial double that replays a script of incoming bytes and records every
+/// outgoing one. `None` entries in the script, as well as reads past its end,
+/// simulate a timeout.
+///
+/// Text is recorded UTF-8 encoded, like `ufmt` does by default, so binary
+/// data only reaches the record intact through `WriteByte`.
+#[derive(Debug, Default)]
+pub struct ScriptedSerial {
+    pub incoming: VecDeque<Option<u8>>,
+    pub outgoing: Vec<u8>,
+}
+
+impl ScriptedSerial {
+    pub fn new() -> Self { Self::default() }
+    pub fn queue(&mut self, bytes: &[u8]) { self.incoming.extend(bytes.iter().copied().map(Some)) }
+    pub fn queue_timeout(&mut self) { self.incoming.push_back(None) }
+}
+
+impl serial::Write for ScriptedSerial {
+    type Error = ScriptedSerialTimeout;
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        self.outgoing.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl serial::WriteByte for ScriptedSerial {
+    type Error = ScriptedSerialTimeout;
+    fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.outgoing.push(byte);
+        Ok(())
+    }
+}
+
+impl serial::Read for ScriptedSerial {
+    type Error = ScriptedSerialTimeout;
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.incoming.pop_front().flatten().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl serial::TimeoutRead for ScriptedSerial {
+    type Error = ScriptedSerialTimeout;
+
+    fn read<T: Copy + Into<time::Milliseconds>>(&mut self, _timeout: T) -> Result<u8, Self::Error> {
+        self.incoming.pop_front().flatten().ok_or(ScriptedSerialTimeout)
+    }
+}
@@ -1,8 +1,13 @@
-use crate::utilities::memory::Address;
+use crate::utilities::{
+    bitwise::SliceBitSubset,
+    memory::{Address, Region},
+};
+use bytemuck::Pod;
 use core::{
     mem::{size_of, MaybeUninit},
     slice,
 };
+use crc::crc32;
 
 /// Reads and writes a range of bytes, generic over an address
 pub trait ReadWrite {
@@ -15,24 +20,645 @@ pub trait ReadWrite {
     fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error>;
     fn range(&self) -> (Self::Address, Self::Address);
     fn erase(&mut self) -> nb::Result<(), Self::Error>;
+    /// Iterates over bytes from an address to the end of the device,
+    /// through an internal buffer. Iteration silently stops on a read error;
+    /// prefer `chunks` or `reader` when errors must be told apart from the
+    /// end of the data.
     fn bytes(&mut self, address: Self::Address) -> ReadIterator<Self> {
+        let end = self.range().1;
+        self.bytes_until(address, end)
+    }
+
+    /// Iterates over bytes in the `[start, end)` range, clamped to the end of
+    /// the device. Iterating from the back is supported, e.g. to search for
+    /// footers.
+    fn bytes_until(&mut self, start: Self::Address, end: Self::Address) -> ReadIterator<'_, Self> {
+        let end = end.min(self.range().1).max(start);
         ReadIterator {
             reader: self,
-            address,
             errored: false,
             buffer: [0u8; ITERATOR_BUFFER_SIZE],
-            buffer_head: ITERATOR_BUFFER_SIZE,
+            window: (start, 0),
+            front: start,
+            back: end,
+        }
+    }
+
+    /// Reads `size` bytes from an address onwards (stopping at the end of the
+    /// device), one caller provided buffer at a time.
+    ///
+    /// # Panics
+    ///
+    /// If the buffer is empty and `size` isn't zero.
+    fn chunks<'b>(
+        &mut self,
+        address: Self::Address,
+        size: usize,
+        buffer: &'b mut [u8],
+    ) -> Chunks<'_, 'b, Self> {
+        assert!(size == 0 || !buffer.is_empty(), "Chunk buffer must not be empty");
+        let size = size.min(available(self, address));
+        Chunks { reader: self, buffer, address, remaining: size }
+    }
+
+    /// Reads `size` bytes from an address onwards (stopping at the end of the
+    /// device), into buffers of any size.
+    fn reader(&mut self, address: Self::Address, size: usize) -> Reader<'_, Self> {
+        let size = size.min(available(self, address));
+        Reader { flash: self, address, remaining: size }
+    }
+
+    /// Writes consecutive blocks from an address onwards. Defaults to one
+    /// `write` per block; drivers may override it to batch blocks together.
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Self::Address,
+        blocks: I,
+    ) -> Result<(), Self::Error> {
+        for (index, block) in blocks.enumerate() {
+            nb::block!(self.write(address + index * N, &block))?;
         }
+        Ok(())
     }
+}
+
+/// Lends a device to code generic over `ReadWrite` (e.g. a `Partition`)
+/// without giving up ownership.
+impl<F: ReadWrite + ?Sized> ReadWrite for &mut F {
+    type Error = F::Error;
+    type Address = F::Address;
 
+    fn label() -> &'static str { F::label() }
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        (**self).read(address, bytes)
+    }
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        (**self).write(address, bytes)
+    }
+    fn range(&self) -> (Self::Address, Self::Address) { (**self).range() }
+    fn erase(&mut self) -> nb::Result<(), Self::Error> { (**self).erase() }
     fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
         &mut self,
         address: Self::Address,
         blocks: I,
-    ) -> Result<(), Self::Error>;
+    ) -> Result<(), Self::Error> {
+        (**self).write_from_blocks(address, blocks)
+    }
+}
+
+/// Erases part of a device, at the granularity of its erase blocks
+/// (sectors, pages, etc).
+pub trait EraseRegion: ReadWrite {
+    /// Erases every block overlapping the `[start, end)` range. Since blocks
+    /// can't be partially erased, any data sharing a block with the range is
+    /// erased as well.
+    fn erase_range(
+        &mut self,
+        start: Self::Address,
+        end: Self::Address,
+    ) -> nb::Result<(), Self::Error>;
+}
+
+impl<F: EraseRegion + ?Sized> EraseRegion for &mut F {
+    fn erase_range(
+        &mut self,
+        start: Self::Address,
+        end: Self::Address,
+    ) -> nb::Result<(), Self::Error> {
+        (**self).erase_range(start, end)
+    }
+}
+
+/// A contiguous span of flash that can only be erased as a whole.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EraseBlock<A: Address> {
+    pub start: A,
+    pub size: usize,
+}
+
+impl<A: Address> EraseBlock<A> {
+    pub fn end(&self) -> A { self.start + self.size }
+    pub fn contains(&self, address: A) -> bool { (address >= self.start) && (address < self.end()) }
+}
+
+impl<A: Address> Region<A> for EraseBlock<A> {
+    fn contains(&self, address: A) -> bool { EraseBlock::contains(self, address) }
+}
+
+/// Physical layout of a flash device, so generic code (bootloaders, wear
+/// levelling, etc) can adapt to the erase and program granularity of the part.
+pub trait Geometry: ReadWrite {
+    /// Number of independently erasable blocks within `range()`.
+    fn erase_block_count(&self) -> usize;
+
+    /// Erasable block at a given index, in ascending address order.
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Self::Address>>;
+
+    /// Required alignment, in bytes, of the address and length of any write.
+    fn write_alignment(&self) -> usize;
+
+    /// Value every byte reads as after an erase.
+    fn erased_value(&self) -> u8 { 0xFF }
+
+    /// Iterates over every erasable block, in address order.
+    fn erase_blocks(&self) -> EraseBlocks<'_, Self> { EraseBlocks { geometry: self, index: 0 } }
+
+    /// Erasable block holding an address, if any.
+    fn erase_block_at(&self, address: Self::Address) -> Option<EraseBlock<Self::Address>> {
+        block_index_at(self, address).and_then(|index| self.erase_block(index))
+    }
+}
+
+impl<G: Geometry + ?Sized> Geometry for &mut G {
+    fn erase_block_count(&self) -> usize { (**self).erase_block_count() }
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Self::Address>> {
+        (**self).erase_block(index)
+    }
+    fn write_alignment(&self) -> usize { (**self).write_alignment() }
+    fn erased_value(&self) -> u8 { (**self).erased_value() }
+}
+
+pub struct EraseBlocks<'a, G: Geometry + ?Sized> {
+    geometry: &'a G,
+    index: usize,
+}
+
+impl<'a, G: Geometry + ?Sized> Iterator for EraseBlocks<'a, G> {
+    type Item = EraseBlock<G::Address>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = self.geometry.erase_block(self.index)?;
+        self.index += 1;
+        Some(block)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.geometry.erase_block_count().saturating_sub(self.index);
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, G: Geometry + ?Sized> ExactSizeIterator for EraseBlocks<'a, G> {}
+
+/// Snapshot of a long running write or erase, passed to a `ProgressHook`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Progress<A: Address> {
+    /// Bytes written or erased so far.
+    pub done: usize,
+    /// Bytes to write or erase in total, if known up front.
+    pub total: Option<usize>,
+    /// Start of the sector (or page) being written or erased.
+    pub sector: A,
+}
+
+/// Called periodically during long operations, e.g. to blink an LED or
+/// print to a console. Keep it short, as it runs between flash cycles.
+pub type ProgressHook<A> = fn(Progress<A>);
+
+/// A device that reports the progress of its writes, erases and block
+/// writes, which can otherwise take tens of seconds.
+pub trait ReportProgress: ReadWrite {
+    /// Sets the hook to call with progress updates, or clears it with `None`.
+    fn set_progress_hook(&mut self, hook: Option<ProgressHook<Self::Address>>);
+}
+
+/// Exact number of bytes produced by an iterator of blocks, if known, for
+/// reporting the progress of block writes.
+pub fn blocks_total<I: Iterator>(blocks: &I, block_size: usize) -> Option<usize> {
+    match blocks.size_hint() {
+        (lower, Some(upper)) if lower == upper => Some(lower * block_size),
+        _ => None,
+    }
+}
+
+/// Writes blocks from an iterator through a staging buffer, for
+/// `ReadWrite::write_from_blocks` on devices where each write is costly to
+/// start, without holding a whole sector on the stack. Blocks larger than
+/// the buffer are written on their own. Progress is reported after each
+/// write, if a hook is given.
+pub fn write_buffered<F, I, const N: usize>(
+    flash: &mut F,
+    address: F::Address,
+    blocks: I,
+    buffer: &mut [u8],
+    progress: Option<ProgressHook<F::Address>>,
+) -> Result<(), F::Error>
+where
+    F: ReadWrite + ?Sized,
+    I: Iterator<Item = [u8; N]>,
+{
+    let total = blocks_total(&blocks, N);
+    let (mut done, mut staged) = (0, 0);
+    let flush = |flash: &mut F, bytes: &[u8], done: &mut usize| {
+        let start = address + *done;
+        nb::block!(flash.write(start, bytes))?;
+        *done += bytes.len();
+        if let Some(hook) = progress {
+            hook(Progress { done: *done, total, sector: start });
+        }
+        Ok(())
+    };
+
+    for block in blocks {
+        if staged + N > buffer.len() && staged > 0 {
+            flush(flash, &buffer[..staged], &mut done)?;
+            staged = 0;
+        }
+        if N > buffer.len() {
+            flush(flash, &block, &mut done)?;
+        } else {
+            buffer[staged..staged + N].copy_from_slice(&block);
+            staged += N;
+        }
+    }
+    if staged > 0 {
+        flush(flash, &buffer[..staged], &mut done)?;
+    }
+    Ok(())
+}
+
+/// A device whose program and erase cycles run in the background, so long
+/// operations can be driven from a superloop (through `Erase` and `Program`)
+/// and interleaved with other work such as feeding a watchdog.
+pub trait Background: Geometry {
+    /// Whether a program or erase cycle is still running.
+    fn cycle_in_progress(&mut self) -> Result<bool, Self::Error>;
+
+    /// Starts erasing a block, returning as soon as the cycle is underway, or
+    /// `WouldBlock` without starting it while another cycle is running.
+    fn begin_erase(&mut self, block: EraseBlock<Self::Address>) -> nb::Result<(), Self::Error>;
+
+    /// Starts programming as much of `bytes` as the device takes in a single
+    /// cycle (e.g. up to the end of a page) over erased memory, returning how
+    /// many bytes were accepted. At least one byte must be accepted. Returns
+    /// `WouldBlock` without starting it while another cycle is running.
+    fn begin_program(
+        &mut self,
+        address: Self::Address,
+        bytes: &[u8],
+    ) -> nb::Result<usize, Self::Error>;
+
+    /// Blocks until the running cycle (if any) completes. Drivers with a
+    /// timeout override this to give up on an unresponsive device.
+    fn wait_until_idle(&mut self) -> Result<(), Self::Error> {
+        while self.cycle_in_progress()? {}
+        Ok(())
+    }
+}
+
+/// Resumable erase of every block overlapping a range.
+pub struct Erase<A: Address> {
+    next: A,
+    end: A,
+}
+
+impl<A: Address> Erase<A> {
+    pub fn new(start: A, end: A) -> Self { Self { next: start, end } }
+
+    /// Starts erasing the next block whenever the device is idle, returning
+    /// `WouldBlock` until the whole range is erased.
+    pub fn poll<F: Background<Address = A>>(&mut self, flash: &mut F) -> nb::Result<(), F::Error> {
+        if flash.cycle_in_progress()? {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let (next, end) = (self.next, self.end);
+        match flash.erase_blocks().find(|block| (block.end() > next) && (block.start < end)) {
+            Some(block) => {
+                flash.begin_erase(block)?;
+                self.next = block.end();
+                Err(nb::Error::WouldBlock)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Resumable write of a buffer over erased memory.
+pub struct Program<'a, A: Address> {
+    address: A,
+    bytes: &'a [u8],
+}
+
+impl<'a, A: Address> Program<'a, A> {
+    pub fn new(address: A, bytes: &'a [u8]) -> Self { Self { address, bytes } }
+
+    /// Starts programming the next part of the buffer whenever the device is
+    /// idle, returning `WouldBlock` until the whole buffer is written.
+    pub fn poll<F: Background<Address = A>>(&mut self, flash: &mut F) -> nb::Result<(), F::Error> {
+        if flash.cycle_in_progress()? {
+            return Err(nb::Error::WouldBlock);
+        } else if self.bytes.is_empty() {
+            return Ok(());
+        }
+
+        let accepted = flash.begin_program(self.address, self.bytes)?;
+        self.address = self.address + accepted;
+        self.bytes = &self.bytes[accepted..];
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MergeError<E> {
+    Flash(E),
+    /// An erase is needed, but the rest of the block doesn't fit in the
+    /// scratch buffer, which would have to hold `required` bytes.
+    ScratchTooSmall {
+        required: usize,
+    },
+    /// Part of the write falls outside every erase block.
+    OutOfBounds,
+}
+
+/// A device whose writes merge new data with the existing contents of a
+/// block (see `merge_write`) through a caller supplied scratch buffer,
+/// instead of a sector sized array on the stack.
+pub trait MergeScratch: ReadWrite {
+    /// Sets the buffer holding block contents across erases, or falls back
+    /// to the driver's small default with `None`.
+    fn set_scratch_buffer(&mut self, scratch: Option<&'static mut [u8]>);
+}
+
+/// Writes anywhere on a device that can only erase whole blocks and program
+/// erased memory, blocking until done. A block is only erased when the new
+/// bytes aren't a bitwise subset of its contents; the rest of the block is
+/// then held in `scratch` and restored after the erase. Words the write only
+/// partly covers (see `Geometry::write_alignment`) are completed from their
+/// current contents, so any address and length can be written.
+///
+/// Only the blocks at either end of a write can be partly covered, so they
+/// are checked before any block is modified: a write that can't be merged
+/// leaves the device untouched (at the cost of reading those blocks twice).
+/// Progress is reported after each block, if a hook is given.
+///
+/// # Panics
+///
+/// If the scratch buffer is empty.
+pub fn merge_write<F: Background>(
+    flash: &mut F,
+    address: F::Address,
+    bytes: &[u8],
+    scratch: &mut [u8],
+    progress: Option<ProgressHook<F::Address>>,
+) -> Result<(), MergeError<F::Error>> {
+    assert!(!scratch.is_empty(), "Merge scratch buffer must not be empty");
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    let end = address + bytes.len();
+    let (first, last) = match (block_index_at(flash, address), block_index_at(flash, end - 1)) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err(MergeError::OutOfBounds),
+    };
+    let within = |block: EraseBlock<F::Address>| {
+        let (start, end) = (block.start.max(address), block.end().min(end));
+        (start, &bytes[start - address..end - address])
+    };
+
+    let edges = if first == last { &[first][..] } else { &[first, last][..] };
+    for index in edges {
+        let block = flash.erase_block(*index).ok_or(MergeError::OutOfBounds)?;
+        let (block_address, block_bytes) = within(block);
+        plan_merge(flash, block, block_address, block_bytes, scratch)?;
+    }
+
+    let (mut done, mut next) = (0, None);
+    for index in first..=last {
+        let block = flash.erase_block(index).ok_or(MergeError::OutOfBounds)?;
+        if next.is_some_and(|next| next != block.start) {
+            // A gap between blocks is only found on reaching it
+            return Err(MergeError::OutOfBounds);
+        }
+        next = Some(block.end());
+        let (block_address, block_bytes) = within(block);
+        let merge = plan_merge(flash, block, block_address, block_bytes, scratch)?;
+        merge_block(flash, block, block_address, block_bytes, &merge, scratch)?;
+        done += block_bytes.len();
+        if let Some(hook) = progress {
+            hook(Progress { done, total: Some(bytes.len()), sector: block.start });
+        }
+    }
+    Ok(())
+}
+
+/// Index of the erase block holding an address, found by bisection since
+/// blocks are in ascending address order.
+fn block_index_at<G: Geometry + ?Sized>(geometry: &G, address: G::Address) -> Option<usize> {
+    let (mut low, mut high) = (0, geometry.erase_block_count());
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let block = geometry.erase_block(middle)?;
+        if address < block.start {
+            high = middle;
+        } else if address >= block.end() {
+            low = middle + 1;
+        } else {
+            return Some(middle);
+        }
+    }
+    None
+}
+
+/// How a write is merged into an erase block, as offsets into the block.
+/// The `[from, to)` span is programmed from scratch up to `first`, from the
+/// new bytes up to `last`, and from scratch again after that.
+struct Merge {
+    erase: bool,
+    from: usize,
+    first: usize,
+    last: usize,
+    to: usize,
+}
+
+fn plan_merge<F: Background>(
+    flash: &mut F,
+    block: EraseBlock<F::Address>,
+    address: F::Address,
+    bytes: &[u8],
+    scratch: &mut [u8],
+) -> Result<Merge, MergeError<F::Error>> {
+    let mut is_subset = true;
+    let mut offset = 0;
+    let mut chunks = flash.chunks(address, bytes.len(), scratch);
+    while let Some(chunk) = chunks.next_chunk() {
+        let chunk = chunk.map_err(MergeError::Flash)?;
+        is_subset &= bytes[offset..offset + chunk.len()].is_subset_of(chunk);
+        offset += chunk.len();
+    }
+
+    // Whole words of new bytes are programmed directly; the rest of the
+    // programmed span is read into scratch first (just the partial words,
+    // or the whole block when it must be erased).
+    let alignment = flash.write_alignment().max(1);
+    let down = |offset: usize| offset - offset % alignment;
+    let up = |offset: usize| down(offset + alignment - 1).min(block.size);
+    let (start, end) = (address - block.start, address - block.start + bytes.len());
+    let (mut first, mut last) = (up(start), down(end));
+    if first > last {
+        // The write lies within a single word
+        first = down(start);
+        last = first;
+    }
+    let (from, to) = if is_subset { (down(start), up(end)) } else { (0, block.size) };
+
+    let required = (first - from) + (to - last);
+    if required > scratch.len() {
+        return Err(MergeError::ScratchTooSmall { required });
+    }
+    Ok(Merge { erase: !is_subset, from, first, last, to })
+}
+
+fn merge_block<F: Background>(
+    flash: &mut F,
+    block: EraseBlock<F::Address>,
+    address: F::Address,
+    bytes: &[u8],
+    merge: &Merge,
+    scratch: &mut [u8],
+) -> Result<(), MergeError<F::Error>> {
+    let Merge { erase, from, first, last, to } = *merge;
+    let start = address - block.start;
+    let (head, tail) = scratch[..(first - from) + (to - last)].split_at_mut(first - from);
+    for (offset, buffer) in [(from, &mut *head), (last, &mut *tail)] {
+        if !buffer.is_empty() {
+            nb::block!(flash.read(block.start + offset, buffer)).map_err(MergeError::Flash)?;
+        }
+    }
+    for (offset, byte) in (start..).zip(bytes) {
+        if offset < first {
+            head[offset - from] = *byte;
+        } else if offset >= last {
+            tail[offset - last] = *byte;
+        }
+    }
+    let middle = if first < last { &bytes[first - start..last - start] } else { &[] };
+
+    if erase {
+        let mut erase = Erase::new(block.start, block.end());
+        complete(flash, |flash| erase.poll(flash)).map_err(MergeError::Flash)?;
+    }
+    for (offset, bytes) in [(from, &*head), (first, middle), (last, &*tail)] {
+        if bytes.is_empty() {
+            continue;
+        }
+        let mut program = Program::new(block.start + offset, bytes);
+        complete(flash, |flash| program.poll(flash)).map_err(MergeError::Flash)?;
+    }
+    Ok(())
+}
+
+/// Polls an operation until done, waiting out each cycle through
+/// `Background::wait_until_idle`.
+fn complete<F: Background>(
+    flash: &mut F,
+    mut poll: impl FnMut(&mut F) -> nb::Result<(), F::Error>,
+) -> Result<(), F::Error> {
+    loop {
+        match poll(flash) {
+            Ok(()) => return Ok(()),
+            Err(nb::Error::WouldBlock) => flash.wait_until_idle()?,
+            Err(nb::Error::Other(error)) => return Err(error),
+        }
+    }
+}
+
+/// Identifies a record written through `Serialize`, distinguishing it from
+/// erased or unrelated memory.
+pub const RECORD_MAGIC: u32 = 0xB1E5_EC0D;
+
+/// Magic number, type version, payload length and payload CRC32, each
+/// encoded as a little endian `u32`.
+pub const RECORD_HEADER_SIZE: usize = 16;
+
+/// A plain data type that can be stored in flash through `Serialize`. The
+/// version must be bumped whenever the type layout changes, so that stale
+/// records are rejected rather than reinterpreted.
+pub trait Record: Pod {
+    const VERSION: u32;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RecordError<E> {
+    Flash(E),
+    /// No record header at this address (e.g. erased memory).
+    NotFound,
+    /// The record was written by a different version of the type.
+    VersionMismatch {
+        stored: u32,
+        expected: u32,
+    },
+    /// The record length doesn't match the size of the type.
+    SizeMismatch {
+        stored: usize,
+        expected: usize,
+    },
+    /// The record contents don't match their checksum.
+    Corrupted,
+}
+
+/// Safely serializes plain data types to flash, prefixed by a header.
+pub trait Serialize: ReadWrite {
+    /// Writes the payload before the header, so a write interrupted at any
+    /// point is detected when deserializing.
+    fn serialize_record<T: Record>(
+        &mut self,
+        item: &T,
+        address: Self::Address,
+    ) -> nb::Result<(), Self::Error> {
+        let payload = bytemuck::bytes_of(item);
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        let fields =
+            [RECORD_MAGIC, T::VERSION, payload.len() as u32, crc32::checksum_ieee(payload)];
+        header.chunks_mut(4).zip(&fields).for_each(|(b, f)| b.copy_from_slice(&f.to_le_bytes()));
+
+        self.write(address + RECORD_HEADER_SIZE, payload)?;
+        self.write(address, &header)
+    }
+}
+impl<F: ReadWrite> Serialize for F {}
+
+/// Safely deserializes plain data types from flash, verifying their header.
+pub trait Deserialize: ReadWrite {
+    fn deserialize_record<T: Record>(
+        &mut self,
+        address: Self::Address,
+    ) -> nb::Result<T, RecordError<Self::Error>> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.read(address, &mut header).map_err(|e| e.map(RecordError::Flash))?;
+        let mut fields = header.chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let mut field = || fields.next().unwrap_or_default();
+        let (magic, version, length, crc) = (field(), field(), field() as usize, field());
+
+        if magic != RECORD_MAGIC {
+            return Err(nb::Error::Other(RecordError::NotFound));
+        }
+        if version != T::VERSION {
+            return Err(nb::Error::Other(RecordError::VersionMismatch {
+                stored: version,
+                expected: T::VERSION,
+            }));
+        }
+        if length != size_of::<T>() {
+            return Err(nb::Error::Other(RecordError::SizeMismatch {
+                stored: length,
+                expected: size_of::<T>(),
+            }));
+        }
+
+        let mut item = T::zeroed();
+        self.read(address + RECORD_HEADER_SIZE, bytemuck::bytes_of_mut(&mut item))
+            .map_err(|e| e.map(RecordError::Flash))?;
+        if crc32::checksum_ieee(bytemuck::bytes_of(&item)) != crc {
+            return Err(nb::Error::Other(RecordError::Corrupted));
+        }
+        Ok(item)
+    }
 }
+impl<F: ReadWrite> Deserialize for F {}
 
 /// Serialize an object to flash.
+#[deprecated(note = "Undefined behaviour on any layout change, use `Serialize` instead")]
 pub trait UnportableSerialize: ReadWrite {
     /// # Safety
     ///
@@ -51,9 +677,11 @@ pub trait UnportableSerialize: ReadWrite {
         self.write(address, bytes)
     }
 }
+#[allow(deprecated)]
 impl<F: ReadWrite> UnportableSerialize for F {}
 
 /// Deserialize an object from flash.
+#[deprecated(note = "Undefined behaviour on any layout change, use `Deserialize` instead")]
 pub trait UnportableDeserialize: ReadWrite {
     /// # Safety
     ///
@@ -75,41 +703,169 @@ pub trait UnportableDeserialize: ReadWrite {
         Ok(uninit.assume_init())
     }
 }
+#[allow(deprecated)]
 impl<F: ReadWrite> UnportableDeserialize for F {}
 
 const ITERATOR_BUFFER_SIZE: usize = 2048;
 
+/// Bytes left in the device from an address onwards.
+fn available<R: ReadWrite + ?Sized>(flash: &R, address: R::Address) -> usize {
+    let end = flash.range().1;
+    if address < end {
+        end - address
+    } else {
+        0
+    }
+}
+
+/// Byte iterator over a range of flash, see `ReadWrite::bytes`. Both ends
+/// share a single buffer, which is refilled whenever an end moves outside it.
 pub struct ReadIterator<'a, R: ReadWrite + ?Sized> {
     reader: &'a mut R,
     errored: bool,
     buffer: [u8; ITERATOR_BUFFER_SIZE],
-    address: R::Address,
-    buffer_head: usize,
+    /// Start address and length of the buffered data.
+    window: (R::Address, usize),
+    front: R::Address,
+    back: R::Address,
+}
+
+impl<'a, R: ReadWrite + ?Sized> ReadIterator<'a, R> {
+    /// Bytes left in the range, which are all yielded unless a read fails.
+    pub fn remaining(&self) -> usize {
+        if self.errored {
+            0
+        } else {
+            self.back - self.front
+        }
+    }
+
+    fn byte_at(&mut self, address: R::Address) -> Option<u8> {
+        let (start, length) = self.window;
+        if (address < start) || (address >= start + length) {
+            self.fill(address)?;
+        }
+        Some(self.buffer[address - self.window.0])
+    }
+
+    /// Buffers as much of the remaining range as fits, around an address.
+    fn fill(&mut self, address: R::Address) -> Option<()> {
+        let length = (self.back - self.front).min(ITERATOR_BUFFER_SIZE);
+        let start = if address == self.front { self.front } else { address + 1 - length };
+        if nb::block!(self.reader.read(start, &mut self.buffer[..length])).is_err() {
+            self.errored = true;
+            return None;
+        }
+        self.window = (start, length);
+        Some(())
+    }
 }
 
 impl<'a, R: ReadWrite + ?Sized> Iterator for ReadIterator<'a, R> {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.errored {
+        if self.errored || (self.front == self.back) {
             return None;
         }
+        let byte = self.byte_at(self.front)?;
+        self.front = self.front + 1;
+        Some(byte)
+    }
 
-        if self.buffer_head == ITERATOR_BUFFER_SIZE {
-            if nb::block!(self.reader.read(self.address, &mut self.buffer)).is_err() {
-                self.errored = true;
-                return None;
-            };
-            self.buffer_head = 0;
-            self.address = self.address + ITERATOR_BUFFER_SIZE;
-        }
+    /// No lower bound, as a read error ends iteration early.
+    fn size_hint(&self) -> (usize, Option<usize>) { (0, Some(self.remaining())) }
+}
 
-        let byte = self.buffer[self.buffer_head];
-        self.buffer_head += 1;
+impl<'a, R: ReadWrite + ?Sized> DoubleEndedIterator for ReadIterator<'a, R> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.errored || (self.front == self.back) {
+            return None;
+        }
+        let byte = self.byte_at(self.back - 1usize)?;
+        self.back = self.back - 1usize;
         Some(byte)
     }
 }
 
+/// Size of the stack buffers used to stream flash contents, e.g. through
+/// `ReadWrite::chunks`.
+pub const CHUNK_SIZE: usize = 256;
+
+/// Chunked reader over a range of flash, see `ReadWrite::chunks`.
+pub struct Chunks<'a, 'b, R: ReadWrite + ?Sized> {
+    reader: &'a mut R,
+    buffer: &'b mut [u8],
+    address: R::Address,
+    remaining: usize,
+}
+
+impl<'a, 'b, R: ReadWrite + ?Sized> Chunks<'a, 'b, R> {
+    /// Reads the next chunk, at most the size of the buffer. Returns `None`
+    /// once the range is exhausted or after the first error.
+    pub fn next_chunk(&mut self) -> Option<Result<&[u8], R::Error>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let size = self.remaining.min(self.buffer.len());
+        let chunk = &mut self.buffer[..size];
+        if let Err(error) = nb::block!(self.reader.read(self.address, chunk)) {
+            self.remaining = 0;
+            return Some(Err(error));
+        }
+        self.address = self.address + size;
+        self.remaining -= size;
+        Some(Ok(chunk))
+    }
+
+    /// Bytes left to read.
+    pub fn remaining(&self) -> usize { self.remaining }
+
+    /// Feeds every chunk to a closure (e.g. a hasher), stopping at the first
+    /// read error.
+    pub fn try_for_each<C: FnMut(&[u8])>(mut self, mut consume: C) -> Result<(), R::Error> {
+        while let Some(chunk) = self.next_chunk() {
+            consume(chunk?);
+        }
+        Ok(())
+    }
+}
+
+/// Cursor over a range of flash, see `ReadWrite::reader`.
+pub struct Reader<'a, R: ReadWrite + ?Sized> {
+    flash: &'a mut R,
+    address: R::Address,
+    remaining: usize,
+}
+
+impl<'a, R: ReadWrite + ?Sized> Reader<'a, R> {
+    /// Fills as much of `bytes` as the range allows, returning the number of
+    /// bytes read. Zero means the range is exhausted.
+    pub fn read(&mut self, bytes: &mut [u8]) -> Result<usize, R::Error> {
+        let size = self.remaining.min(bytes.len());
+        if size > 0 {
+            nb::block!(self.flash.read(self.address, &mut bytes[..size]))?;
+            self.address = self.address + size;
+            self.remaining -= size;
+        }
+        Ok(size)
+    }
+
+    /// Bytes left to read.
+    pub fn remaining(&self) -> usize { self.remaining }
+
+    /// Address of the next byte to read.
+    pub fn address(&self) -> R::Address { self.address }
+}
+
+#[cfg(not(target_arch = "arm"))]
+impl<'a, R: ReadWrite + ?Sized> std::io::Read for Reader<'a, R> {
+    fn read(&mut self, bytes: &mut [u8]) -> std::io::Result<usize> {
+        Reader::read(self, bytes).map_err(|_| std::io::Error::other("Flash read failed"))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -126,4 +882,349 @@ mod tests {
         let bytes: Vec<u8> = flash.bytes(Address(0)).take(10000).collect();
         assert_eq!(expected_bytes, bytes);
     }
+
+    #[test]
+    fn byte_iteration_ends_cleanly_at_the_end_of_the_device() {
+        // Given
+        let mut flash = FakeFlash::uniform(2, 3000);
+        let (_, end) = flash.range();
+        flash.write(end - 2usize, &[0xAB, 0xCD]).unwrap();
+
+        // When
+        let iterator = flash.bytes(Address(1000));
+        let length = iterator.remaining();
+        let bytes: Vec<u8> = iterator.collect();
+
+        // Then
+        assert_eq!(length, 5000);
+        assert_eq!(bytes.len(), 5000);
+        assert_eq!(&bytes[4998..], &[0xAB, 0xCD]);
+        assert_eq!(flash.bytes_until(Address(10), end + 100usize).remaining(), 5990);
+        assert_eq!(flash.bytes(end).next(), None);
+    }
+
+    #[test]
+    fn iterating_over_fake_flash_from_both_ends() {
+        // Given
+        let expected_bytes: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0), &expected_bytes).unwrap();
+
+        // When
+        let reversed: Vec<u8> = flash.bytes_until(Address(0), Address(5000)).rev().collect();
+        let from_end = flash.bytes_until(Address(0), Address(5000)).rev().position(|b| b == 250);
+        let mut iterator = flash.bytes_until(Address(0), Address(5000));
+        let (first, last) = (iterator.next(), iterator.next_back());
+
+        // Then
+        assert!(reversed.iter().eq(expected_bytes.iter().rev()));
+        assert_eq!((first, last), (Some(0), Some(expected_bytes[4999])));
+        assert_eq!(iterator.remaining(), 4998);
+        assert!(iterator.eq(expected_bytes[1..4999].iter().copied()));
+        assert_eq!(from_end, Some(4999 - 4768));
+    }
+
+    #[test]
+    fn erasing_and_programming_in_the_background() {
+        // Given
+        let mut flash = FakeFlash::uniform(4, 1024);
+        flash.write(Address(0), &[0x00; 4096]).unwrap();
+        flash.set_busy_polls(3);
+        let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let mut erase = Erase::new(Address(1000), Address(2100));
+        let mut program = Program::new(Address(1024), &data);
+
+        // When
+        let mut polls = 0;
+        while erase.poll(&mut flash) == Err(nb::Error::WouldBlock) {
+            polls += 1;
+        }
+        while program.poll(&mut flash) == Err(nb::Error::WouldBlock) {
+            polls += 1;
+        }
+
+        // Then
+        assert_eq!(flash.erase_cycles(), &[1, 1, 1, 0]);
+        assert_eq!(polls, 3 * 4 + 6 * 4);
+        assert_eq!(contents(&mut flash, Address(1024), data.len()), data);
+        assert_eq!(contents(&mut flash, Address(1024 + 1500), 2), [0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn reading_fake_flash_in_chunks() {
+        // Given
+        let expected_bytes: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0x10), &expected_bytes).unwrap();
+        let mut buffer = [0u8; 64];
+
+        // When
+        let mut bytes = Vec::new();
+        let mut sizes = Vec::new();
+        flash
+            .chunks(Address(0x10), expected_bytes.len(), &mut buffer)
+            .try_for_each(|chunk| {
+                sizes.push(chunk.len());
+                bytes.extend_from_slice(chunk);
+            })
+            .unwrap();
+
+        // Then
+        assert_eq!(bytes, expected_bytes);
+        assert_eq!(sizes.len(), 16);
+        assert_eq!(sizes.last(), Some(&(1000 % 64)));
+    }
+
+    #[test]
+    fn chunked_reads_stop_at_the_device_end_and_surface_errors() {
+        // Given
+        let mut flash = FakeFlash::with_layout(Address(0x100), &[(2, 64)], ProgramMode::Strict);
+        let mut buffer = [0u8; 100];
+
+        // When
+        let mut chunks = flash.chunks(Address(0x100), 200, &mut buffer);
+        let mut sizes = Vec::new();
+        while let Some(chunk) = chunks.next_chunk() {
+            sizes.push(chunk.map(<[u8]>::len));
+        }
+        let mut chunks = flash.chunks(Address(0), 100, &mut buffer);
+
+        // Then
+        assert_eq!(sizes, [Ok(100), Ok(28)]);
+        assert_eq!(chunks.next_chunk(), Some(Err(FakeFlashError::OutOfRange)));
+        assert_eq!(chunks.next_chunk(), None);
+    }
+
+    #[test]
+    fn reading_fake_flash_through_a_reader() {
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0), b"blue hal").unwrap();
+        let mut reader = flash.reader(Address(0), 8);
+        let mut bytes = [0u8; 5];
+
+        assert_eq!(reader.read(&mut bytes), Ok(5));
+        assert_eq!(&bytes, b"blue ");
+        assert_eq!(reader.read(&mut bytes), Ok(3));
+        assert_eq!(&bytes[..3], b"hal");
+        assert_eq!(reader.read(&mut bytes), Ok(0));
+
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut flash.reader(Address(0), 8), &mut contents).unwrap();
+        assert_eq!(contents, "blue hal");
+    }
+
+    #[test]
+    fn iterating_over_erase_blocks_of_fake_flash() {
+        let flash = FakeFlash::new(Address(0x1000));
+        let (start, end) = flash.range();
+
+        let blocks: Vec<_> = flash.erase_blocks().collect();
+
+        assert_eq!(blocks.len(), flash.erase_block_count());
+        assert_eq!(blocks[0], EraseBlock { start, size: SECTOR_SIZE });
+        assert_eq!(blocks.last().unwrap().end(), end);
+        assert!(blocks.windows(2).all(|pair| pair[0].end() == pair[1].start));
+        assert_eq!(flash.erase_block_at(start + SECTOR_SIZE + 1), Some(blocks[1]));
+        assert_eq!(flash.erase_block_at(end - 1), blocks.last().copied());
+        assert_eq!(flash.erase_block_at(start - 1), None);
+        assert_eq!(flash.erase_block_at(end), None);
+    }
+
+    #[test]
+    fn buffered_block_writes_stage_blocks_that_fit_the_buffer() {
+        // Given
+        let mut flash = FakeFlash::uniform(2, 1024);
+        let blocks = (0..10u8).map(|i| [i; 8]);
+        let data: Vec<_> = blocks.clone().flatten().collect();
+        let large = core::iter::repeat_n([0x5Au8; 32], 2);
+
+        // When
+        write_buffered(&mut flash, Address(0), blocks, &mut [0u8; 20], None).unwrap();
+        let staged_writes = flash.counters.writes;
+        write_buffered(&mut flash, Address(512), large, &mut [0u8; 20], None).unwrap();
+
+        // Then
+        assert_eq!(staged_writes, 5);
+        assert_eq!(flash.counters.writes, 7);
+        assert_eq!(contents(&mut flash, Address(0), 80), data);
+        assert_eq!(contents(&mut flash, Address(512), 64), vec![0x5A; 64]);
+    }
+
+    #[test]
+    fn erase_blocks_are_found_by_address_on_mixed_layouts() {
+        let layout = [(4, KB!(16)), (1, KB!(64)), (7, KB!(128))];
+        let flash = FakeFlash::with_layout(Address(0x0800_0000), &layout, ProgramMode::Strict);
+
+        for block in flash.erase_blocks() {
+            for address in [block.start, block.start + block.size / 2, block.end() - 1] {
+                assert_eq!(flash.erase_block_at(address), Some(block));
+            }
+        }
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Settings {
+        serial_number: u32,
+        calibration: [u16; 2],
+    }
+
+    // NOTE(Safety): Settings is repr(C), has no padding and every bit pattern is valid.
+    unsafe impl bytemuck::Zeroable for Settings {}
+    unsafe impl Pod for Settings {}
+    impl Record for Settings {
+        const VERSION: u32 = 3;
+    }
+
+    const SETTINGS: Settings = Settings { serial_number: 0xC0FFEE, calibration: [12, 34] };
+
+    #[test]
+    fn serializing_and_deserializing_records() {
+        let mut flash = FakeFlash::new(Address(0));
+
+        flash.serialize_record(&SETTINGS, Address(0x100)).unwrap();
+
+        assert_eq!(flash.deserialize_record::<Settings>(Address(0x100)), Ok(SETTINGS));
+    }
+
+    #[test]
+    fn deserializing_from_erased_flash_finds_no_record() {
+        let mut flash = FakeFlash::new(Address(0));
+        assert_eq!(
+            flash.deserialize_record::<Settings>(Address(0)),
+            Err(nb::Error::Other(RecordError::NotFound))
+        );
+    }
+
+    #[test]
+    fn stale_or_corrupted_records_are_rejected() {
+        // Given
+        #[repr(C)]
+        #[derive(Copy, Clone, Debug, PartialEq)]
+        struct NewSettings {
+            serial_number: u32,
+        }
+        unsafe impl bytemuck::Zeroable for NewSettings {}
+        unsafe impl Pod for NewSettings {}
+        impl Record for NewSettings {
+            const VERSION: u32 = 4;
+        }
+        let mut flash = FakeFlash::new(Address(0));
+        flash.serialize_record(&SETTINGS, Address(0)).unwrap();
+
+        // When
+        let stale = flash.deserialize_record::<NewSettings>(Address(0));
+        flash.write(Address(RECORD_HEADER_SIZE as u32), &[0x00]).unwrap();
+        let corrupted = flash.deserialize_record::<Settings>(Address(0));
+
+        // Then
+        assert_eq!(
+            stale,
+            Err(nb::Error::Other(RecordError::VersionMismatch { stored: 3, expected: 4 }))
+        );
+        assert_eq!(corrupted, Err(nb::Error::Other(RecordError::Corrupted)));
+    }
+
+    #[test]
+    fn merged_writes_only_erase_when_bits_must_be_set() {
+        // Given
+        let mut flash = FakeFlash::uniform(4, 1024);
+        let mut scratch = [0u8; 1024];
+        flash.write(Address(1000), &[0x0F; 100]).unwrap();
+
+        // When
+        merge_write(&mut flash, Address(1010), &[0x05; 10], &mut scratch, None).unwrap();
+        let cycles_after_subset = flash.erase_cycles().to_vec();
+        merge_write(&mut flash, Address(1020), &[0xF0; 1500], &mut scratch, None).unwrap();
+
+        // Then
+        assert_eq!(cycles_after_subset, vec![0, 0, 0, 0]);
+        assert_eq!(flash.erase_cycles(), &[1, 1, 0, 0]);
+        let bytes = contents(&mut flash, Address(990), 1600);
+        assert_eq!(bytes[..10], [0xFF; 10]);
+        assert_eq!(bytes[10..20], [0x0F; 10]);
+        assert_eq!(bytes[20..30], [0x05; 10]);
+        assert_eq!(bytes[30..1530], [0xF0; 1500][..]);
+        assert_eq!(bytes[1530..], [0xFF; 70][..]);
+    }
+
+    #[test]
+    fn merged_writes_need_scratch_for_the_rest_of_the_block() {
+        let mut flash = FakeFlash::uniform(2, 1024);
+        let mut scratch = [0u8; 256];
+        flash.write(Address(0), &[0x00; 16]).unwrap();
+
+        let result = merge_write(&mut flash, Address(0), &[0xFF; 16], &mut scratch, None);
+
+        assert_eq!(result, Err(MergeError::ScratchTooSmall { required: 1008 }));
+        assert_eq!(flash.erase_cycles(), &[0, 0]);
+        merge_write(&mut flash, Address(0), &[0xFF; 1024], &mut scratch, None).unwrap();
+    }
+
+    #[test]
+    fn merged_writes_check_every_block_before_changing_any() {
+        // Given
+        let mut flash = FakeFlash::uniform(2, 1024);
+        let mut scratch = [0u8; 256];
+        flash.write(Address(1024), &[0x00; 16]).unwrap();
+
+        // When
+        let result = merge_write(&mut flash, Address(1000), &[0xAA; 40], &mut scratch, None);
+
+        // Then
+        assert_eq!(result, Err(MergeError::ScratchTooSmall { required: 1008 }));
+        assert_eq!(flash.counters.writes, 1);
+        assert_eq!(flash.erase_cycles(), &[0, 0]);
+    }
+
+    #[test]
+    fn merged_writes_complete_partial_words_on_aligned_devices() {
+        // Given
+        let mut flash = FakeFlash::uniform(2, 1024);
+        flash.set_write_alignment(4);
+        let mut scratch = [0u8; 1024];
+        flash.write(Address(0), &[0x11; 8]).unwrap();
+        let data = [0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+
+        // When
+        merge_write(&mut flash, Address(5), &data, &mut scratch, None).unwrap();
+        merge_write(&mut flash, Address(1030), &[0x00; 3], &mut scratch, None).unwrap();
+
+        // Then
+        assert_eq!(flash.erase_cycles(), &[1, 0]);
+        let mut bytes = [0u8; 16];
+        flash.read(Address(0), &mut bytes).unwrap();
+        assert_eq!(bytes[..5], [0x11; 5]);
+        assert_eq!(bytes[5..12], data);
+        assert_eq!(bytes[12..], [0xFF; 4]);
+        flash.read(Address(1028), &mut bytes[..8]).unwrap();
+        assert_eq!(bytes[..8], [0xFF, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn merged_writes_outside_every_block_change_nothing() {
+        let mut flash = FakeFlash::uniform(2, 1024);
+        let mut scratch = [0u8; 1024];
+
+        let result = merge_write(&mut flash, Address(2040), &[0x00; 16], &mut scratch, None);
+
+        assert_eq!(result, Err(MergeError::OutOfBounds));
+        assert_eq!(flash.counters.writes, 0);
+    }
+
+    #[test]
+    fn erasing_a_range_of_fake_flash_erases_whole_sectors() {
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0), &[0x00; 3 * SECTOR_SIZE]).unwrap();
+
+        flash
+            .erase_range(Address(SECTOR_SIZE as u32 + 1), Address(SECTOR_SIZE as u32 + 2))
+            .unwrap();
+
+        let mut bytes = [0u8; 3 * SECTOR_SIZE];
+        flash.read(Address(0), &mut bytes).unwrap();
+        assert!(bytes[..SECTOR_SIZE].iter().all(|b| *b == 0x00));
+        assert!(bytes[SECTOR_SIZE..2 * SECTOR_SIZE].iter().all(|b| *b == 0xFF));
+        assert!(bytes[2 * SECTOR_SIZE..].iter().all(|b| *b == 0x00));
+    }
 }
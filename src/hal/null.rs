@@ -1,6 +1,6 @@
 use super::{
     flash,
-    serial::{Read, TimeoutRead, Write},
+    serial::{Read, TimeoutRead, Write, WriteByte},
     time,
 };
 
@@ -22,6 +22,11 @@ impl Write for NullSerial {
     fn write_str(&mut self, _: &str) -> Result<(), Self::Error> { unimplemented!() }
 }
 
+impl WriteByte for NullSerial {
+    type Error = NullError;
+    fn write_byte(&mut self, _: u8) -> nb::Result<(), Self::Error> { unimplemented!() }
+}
+
 impl TimeoutRead for NullSerial {
     type Error = NullError;
     fn read<T: Copy + Into<super::time::Milliseconds>>(&mut self, _: T) -> Result<u8, Self::Error> {
@@ -13,6 +13,16 @@ impl<T: Read + Write> ReadWrite for T {}
 
 pub use ufmt::uWrite as Write;
 
+/// UART write half, for binary protocols. Unlike `Write::write_char`, which
+/// may UTF-8 encode anything past `0x7F` into several bytes, every byte
+/// reaches the line as is.
+pub trait WriteByte {
+    type Error: Copy + Clone;
+
+    /// Writes a single byte
+    fn write_byte(&mut self, byte: u8) -> nb::Result<(), Self::Error>;
+}
+
 use super::time::Milliseconds;
 
 /// UART read half
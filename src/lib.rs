@@ -20,13 +20,23 @@ pub use efm32gg11b as efm32pac;
 
 #[macro_use]
 pub mod utilities {
+    pub mod banks;
     pub mod bitwise;
     pub mod buffer;
+    pub mod circular_log;
+    pub mod copy;
+    pub mod filesystem;
     pub mod guard;
+    pub mod image;
     pub mod iterator;
+    pub mod kv_store;
     mod macros;
     pub mod memory;
+    pub mod partition;
+    pub mod signature;
+    pub mod verifying_flash;
     pub mod xmodem;
+    pub mod ymodem;
 }
 
 pub use paste;
@@ -0,0 +1,477 @@
+//! Firmware banks for A/B bootloaders.
+//!
+//! Banks are named regions of any flash device, identified by their index in
+//! a table owned by the application. The `Manager` persists which bank is
+//! active, which one is pending to be booted, and whether the active image
+//! has been confirmed, rolling back to the previous bank if a trial boot is
+//! never confirmed.
+//!
+//! Copies between banks can be resumed after a power loss by repeating them:
+//! blocks that already match the source are skipped. Swaps are journaled
+//! through the manager's storage, so they resume at the interrupted step.
+use crate::{
+    hal::flash::{EraseRegion, Geometry, ReadWrite},
+    utilities::{
+        copy::{self as stream, Between, Comparison, Endpoints, Within},
+        kv_store,
+        memory::Address,
+    },
+};
+
+/// Size of the encoded `State`.
+pub const STATE_SIZE: usize = 5;
+const KEY: &[u8] = b"banks";
+const NONE: u8 = 0xFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bank<A: Address> {
+    pub name: &'static str,
+    pub location: A,
+    pub size: usize,
+}
+
+impl<A: Address> Bank<A> {
+    pub fn end(&self) -> A { self.location + self.size }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    /// Bank holding the image to boot.
+    pub active: u8,
+    /// Bank holding a new image to try on the next boot.
+    pub pending: Option<u8>,
+    /// Bank to return to if the active image is rolled back.
+    pub previous: Option<u8>,
+    /// Whether the active image has been confirmed to work.
+    pub confirmed: bool,
+    /// Next step of an interrupted swap.
+    swap_step: Option<u8>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self { active: 0, pending: None, previous: None, confirmed: true, swap_step: None }
+    }
+}
+
+impl State {
+    fn encode(&self) -> [u8; STATE_SIZE] {
+        let encode = |bank: Option<u8>| bank.unwrap_or(NONE);
+        [
+            self.active,
+            encode(self.pending),
+            encode(self.previous),
+            self.confirmed as u8,
+            encode(self.swap_step),
+        ]
+    }
+
+    fn decode(bytes: &[u8; STATE_SIZE]) -> Self {
+        let decode = |byte: u8| (byte != NONE).then_some(byte);
+        Self {
+            active: bytes[0],
+            pending: decode(bytes[1]),
+            previous: decode(bytes[2]),
+            confirmed: bytes[3] != 0,
+            swap_step: decode(bytes[4]),
+        }
+    }
+}
+
+/// Persistent storage for the bank state.
+pub trait Storage {
+    type Error: Copy;
+    /// Loads the last saved state, if any.
+    fn load(&mut self, state: &mut [u8; STATE_SIZE]) -> Result<bool, Self::Error>;
+    fn save(&mut self, state: &[u8; STATE_SIZE]) -> Result<(), Self::Error>;
+}
+
+impl<'a, F: EraseRegion> Storage for kv_store::Store<'a, F> {
+    type Error = kv_store::Error<F::Error>;
+
+    fn load(&mut self, state: &mut [u8; STATE_SIZE]) -> Result<bool, Self::Error> {
+        Ok(self.get(KEY, state)? == Some(STATE_SIZE))
+    }
+
+    fn save(&mut self, state: &[u8; STATE_SIZE]) -> Result<(), Self::Error> { self.set(KEY, state) }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CopyError<S, T> {
+    Source(S),
+    Target(T),
+    /// Source and target banks have different sizes.
+    SizeMismatch,
+    /// The target bank doesn't start and end at erase block boundaries.
+    Misaligned,
+    /// A bank runs past the end of its device.
+    OutOfRange,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SwapError<E, X, Y> {
+    Storage(E),
+    /// Error from the device holding the first bank.
+    First(X),
+    /// Error from the device holding the second bank and the scratch bank.
+    Second(Y),
+    SizeMismatch,
+    Misaligned,
+    OutOfRange,
+}
+
+type SwapResult<S, X, Y> = Result<(), SwapError<<S as Storage>::Error, X, Y>>;
+
+pub struct Manager<S: Storage> {
+    storage: S,
+    state: State,
+}
+
+impl<S: Storage> Manager<S> {
+    /// Loads the bank state, defaulting to a confirmed bank 0.
+    pub fn load(mut storage: S) -> Result<Self, S::Error> {
+        let mut bytes = [0u8; STATE_SIZE];
+        let state =
+            if storage.load(&mut bytes)? { State::decode(&bytes) } else { State::default() };
+        Ok(Self { storage, state })
+    }
+
+    pub fn state(&self) -> State { self.state }
+    pub fn into_storage(self) -> S { self.storage }
+
+    /// Whether a swap was interrupted, and must be resumed by calling
+    /// `swap` (or `swap_within`) again with the same banks.
+    pub fn is_swapping(&self) -> bool { self.state.swap_step.is_some() }
+
+    /// Marks a bank holding a new image, to be tried on the next boot.
+    pub fn set_pending(&mut self, bank: u8) -> Result<(), S::Error> {
+        self.update(|state| state.pending = Some(bank))
+    }
+
+    /// Decides which bank to boot. A pending bank is booted on trial, and
+    /// a trial that wasn't confirmed before the next boot is rolled back.
+    pub fn select_boot_bank(&mut self) -> Result<u8, S::Error> {
+        if let Some(pending) = self.state.pending {
+            self.update(|state| {
+                state.previous = Some(state.active);
+                state.active = pending;
+                state.pending = None;
+                state.confirmed = false;
+            })?;
+        } else if !self.state.confirmed {
+            self.rollback()?;
+        }
+        Ok(self.state.active)
+    }
+
+    /// Confirms the active image works, so it's booted from then on.
+    pub fn confirm(&mut self) -> Result<(), S::Error> {
+        self.update(|state| state.confirmed = true)
+    }
+
+    /// Returns to the previously active bank, if any.
+    pub fn rollback(&mut self) -> Result<(), S::Error> {
+        self.update(|state| {
+            if let Some(previous) = state.previous.take() {
+                state.active = previous;
+            }
+            state.pending = None;
+            state.confirmed = true;
+        })
+    }
+
+    /// Swaps the contents of a bank in one device with a bank in another,
+    /// through a scratch bank in the second device.
+    pub fn swap<X, Y>(
+        &mut self,
+        first: &mut X,
+        a: &Bank<X::Address>,
+        second: &mut Y,
+        b: &Bank<Y::Address>,
+        scratch: &Bank<Y::Address>,
+    ) -> SwapResult<S, X::Error, Y::Error>
+    where
+        X: EraseRegion + Geometry,
+        Y: EraseRegion + Geometry,
+    {
+        self.journaled(|step| match step {
+            0 => copy(first, a, second, scratch).map_err(SwapError::from),
+            1 => copy(second, b, first, a).map_err(|e| SwapError::from(e.flip())),
+            _ => copy_within(second, scratch, b).map_err(|e| SwapError::from(e.into_second())),
+        })
+    }
+
+    /// Swaps the contents of two banks in the same device, through a scratch bank.
+    pub fn swap_within<F: EraseRegion + Geometry>(
+        &mut self,
+        flash: &mut F,
+        a: &Bank<F::Address>,
+        b: &Bank<F::Address>,
+        scratch: &Bank<F::Address>,
+    ) -> SwapResult<S, F::Error, F::Error> {
+        self.journaled(|step| {
+            let (from, to) = [(a, scratch), (b, a), (scratch, b)][step as usize];
+            copy_within(flash, from, to).map_err(SwapError::from)
+        })
+    }
+
+    /// Runs the three steps of a swap, recording progress after each one.
+    fn journaled<X, Y>(
+        &mut self,
+        mut step: impl FnMut(u8) -> Result<(), SwapError<S::Error, X, Y>>,
+    ) -> Result<(), SwapError<S::Error, X, Y>> {
+        let first = self.state.swap_step.unwrap_or(0);
+        for current in first..3 {
+            self.update(|state| state.swap_step = Some(current)).map_err(SwapError::Storage)?;
+            step(current)?;
+        }
+        self.update(|state| state.swap_step = None).map_err(SwapError::Storage)
+    }
+
+    fn update(&mut self, change: impl FnOnce(&mut State)) -> Result<(), S::Error> {
+        let mut state = self.state;
+        change(&mut state);
+        if state != self.state {
+            self.storage.save(&state.encode())?;
+            self.state = state;
+        }
+        Ok(())
+    }
+}
+
+/// Copies a bank into a bank of another device. Target blocks that already
+/// match the source are left untouched, so an interrupted copy can be
+/// resumed by repeating it.
+pub fn copy<S: ReadWrite, T: EraseRegion + Geometry>(
+    source: &mut S,
+    from: &Bank<S::Address>,
+    target: &mut T,
+    to: &Bank<T::Address>,
+) -> Result<(), CopyError<S::Error, T::Error>> {
+    transfer(&mut Between(source, target), from, to)
+}
+
+/// Copies a bank into another bank of the same device, like `copy`.
+pub fn copy_within<F: EraseRegion + Geometry>(
+    flash: &mut F,
+    from: &Bank<F::Address>,
+    to: &Bank<F::Address>,
+) -> Result<(), CopyError<F::Error, F::Error>> {
+    transfer(&mut Within(flash), from, to)
+}
+
+type EndpointsError<E> = CopyError<
+    <<E as Endpoints>::Source as ReadWrite>::Error,
+    <<E as Endpoints>::Destination as ReadWrite>::Error,
+>;
+
+fn transfer<E: Endpoints>(
+    endpoints: &mut E,
+    from: &Bank<<E::Source as ReadWrite>::Address>,
+    to: &Bank<<E::Destination as ReadWrite>::Address>,
+) -> Result<(), EndpointsError<E>>
+where
+    E::Destination: EraseRegion + Geometry,
+{
+    if from.size != to.size {
+        return Err(CopyError::SizeMismatch);
+    }
+    let target = endpoints.destination();
+    let starts_aligned = target.erase_block_at(to.location).map(|b| b.start) == Some(to.location);
+    let ends_aligned = target.erase_block_at(to.end() - 1).map(|b| b.end()) == Some(to.end());
+    if !starts_aligned || !ends_aligned {
+        return Err(CopyError::Misaligned);
+    }
+
+    let mut index = 0;
+    while let Some(block) = endpoints.destination().erase_block(index) {
+        index += 1;
+        if (block.start < to.location) || (block.end() > to.end()) {
+            continue;
+        }
+        let source_start = from.location + (block.start - to.location);
+        let range = (source_start, source_start + block.size);
+        if stream::compare_between(endpoints, range, block.start, None)? != Comparison::Equal {
+            nb::block!(endpoints.destination().erase_range(block.start, block.end()))
+                .map_err(CopyError::Target)?;
+            stream::copy_between(endpoints, range, block.start, None)?;
+        }
+    }
+    Ok(())
+}
+
+impl<S, T> From<stream::Error<S, T>> for CopyError<S, T> {
+    fn from(error: stream::Error<S, T>) -> Self {
+        match error {
+            stream::Error::Source(e) => CopyError::Source(e),
+            stream::Error::Destination(e) => CopyError::Target(e),
+            stream::Error::OutOfRange => CopyError::OutOfRange,
+        }
+    }
+}
+
+impl<S, T> CopyError<S, T> {
+    fn flip(self) -> CopyError<T, S> {
+        match self {
+            CopyError::Source(e) => CopyError::Target(e),
+            CopyError::Target(e) => CopyError::Source(e),
+            CopyError::SizeMismatch => CopyError::SizeMismatch,
+            CopyError::Misaligned => CopyError::Misaligned,
+            CopyError::OutOfRange => CopyError::OutOfRange,
+        }
+    }
+}
+
+impl<T> CopyError<T, T> {
+    /// Attributes errors of a copy within the second device of a swap.
+    fn into_second<X>(self) -> CopyError<X, T> {
+        match self {
+            CopyError::Source(e) | CopyError::Target(e) => CopyError::Target(e),
+            CopyError::SizeMismatch => CopyError::SizeMismatch,
+            CopyError::Misaligned => CopyError::Misaligned,
+            CopyError::OutOfRange => CopyError::OutOfRange,
+        }
+    }
+}
+
+impl<E, X, Y> From<CopyError<X, Y>> for SwapError<E, X, Y> {
+    fn from(error: CopyError<X, Y>) -> Self {
+        match error {
+            CopyError::Source(e) => SwapError::First(e),
+            CopyError::Target(e) => SwapError::Second(e),
+            CopyError::SizeMismatch => SwapError::SizeMismatch,
+            CopyError::Misaligned => SwapError::Misaligned,
+            CopyError::OutOfRange => SwapError::OutOfRange,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::doubles::{
+        faulty_flash::{Fault, FaultyFlash, Tear},
+        flash::{contents, fill, Address, FakeFlash},
+    };
+
+    const BLOCK: usize = 512;
+    const A: Bank<Address> = Bank { name: "a", location: Address(0), size: 2 * BLOCK };
+    const B: Bank<Address> =
+        Bank { name: "b", location: Address(2 * BLOCK as u32), size: 2 * BLOCK };
+    const SCRATCH: Bank<Address> =
+        Bank { name: "scratch", location: Address(4 * BLOCK as u32), size: 2 * BLOCK };
+
+    fn store(flash: &mut FakeFlash) -> kv_store::Store<'_, FakeFlash> {
+        let sectors = [flash.erase_block(0).unwrap(), flash.erase_block(1).unwrap()];
+        kv_store::Store::mount(flash, sectors).unwrap()
+    }
+
+    #[test]
+    fn unconfirmed_trial_boots_are_rolled_back() {
+        // Given
+        let mut flash = FakeFlash::uniform(2, 1024);
+        let mut manager = Manager::load(store(&mut flash)).unwrap();
+        assert_eq!(manager.select_boot_bank(), Ok(0));
+        manager.set_pending(1).unwrap();
+
+        // When
+        let trial = manager.select_boot_bank().unwrap();
+        let mut manager = Manager::load(manager.into_storage()).unwrap();
+        let after_reset = manager.select_boot_bank().unwrap();
+
+        // Then
+        assert_eq!((trial, after_reset), (1, 0));
+        assert_eq!(manager.state(), State { confirmed: true, ..State::default() });
+    }
+
+    #[test]
+    fn confirmed_trial_boots_persist() {
+        let mut flash = FakeFlash::uniform(2, 1024);
+        let mut manager = Manager::load(store(&mut flash)).unwrap();
+        manager.set_pending(1).unwrap();
+        assert_eq!(manager.select_boot_bank(), Ok(1));
+
+        manager.confirm().unwrap();
+
+        let mut manager = Manager::load(manager.into_storage()).unwrap();
+        assert_eq!(manager.select_boot_bank(), Ok(1));
+        manager.rollback().unwrap();
+        assert_eq!(manager.select_boot_bank(), Ok(0));
+    }
+
+    #[test]
+    fn copying_banks_across_devices() {
+        let (mut internal, mut external) =
+            (FakeFlash::uniform(6, BLOCK), FakeFlash::uniform(6, BLOCK));
+        fill(&mut external, B.location, B.size, 0x42);
+
+        copy(&mut external, &B, &mut internal, &A).unwrap();
+
+        assert_eq!(contents(&mut internal, A.location, A.size), vec![0x42; A.size]);
+        let unaligned = Bank { name: "unaligned", location: Address(1), size: A.size };
+        assert_eq!(copy(&mut external, &B, &mut internal, &unaligned), Err(CopyError::Misaligned));
+    }
+
+    #[test]
+    fn interrupted_copies_resume_where_they_stopped() {
+        // Given
+        let mut source = FakeFlash::uniform(6, BLOCK);
+        let mut target = FaultyFlash::new(FakeFlash::uniform(6, BLOCK));
+        fill(&mut source, A.location, A.size, 0x11);
+        fill(&mut target, B.location, B.size, 0x00);
+        let tear = Tear::Randomised { seed: 5 };
+        target.inject(Fault::PowerLossOnWrite { write: 3, programmed: 10, tear });
+
+        // When
+        assert!(copy(&mut source, &A, &mut target, &B).is_err());
+        target.restore_power();
+        copy(&mut source, &A, &mut target, &B).unwrap();
+
+        // Then
+        assert_eq!(contents(&mut target, B.location, B.size), vec![0x11; B.size]);
+        assert_eq!(target.inner.erase_cycles(), &[0, 0, 1, 2, 0, 0]);
+    }
+
+    #[test]
+    fn interrupted_swaps_resume_from_the_journal() {
+        let mut state = FakeFlash::uniform(2, 1024);
+        for write in (0..12).step_by(3) {
+            // Given
+            let mut flash = FaultyFlash::new(FakeFlash::uniform(6, BLOCK));
+            fill(&mut flash, A.location, A.size, 0xAA);
+            fill(&mut flash, B.location, B.size, 0xBB);
+            flash.inject(Fault::PowerLossOnWrite { write, programmed: 1, tear: Tear::Untouched });
+            state.erase().unwrap();
+
+            // When
+            let mut manager = Manager::load(store(&mut state)).unwrap();
+            assert!(manager.swap_within(&mut flash, &A, &B, &SCRATCH).is_err());
+            flash.restore_power();
+            let mut manager = Manager::load(manager.into_storage()).unwrap();
+            assert!(manager.is_swapping());
+            manager.swap_within(&mut flash, &A, &B, &SCRATCH).unwrap();
+
+            // Then
+            assert!(!manager.is_swapping());
+            assert_eq!(contents(&mut flash, A.location, A.size), vec![0xBB; A.size]);
+            assert_eq!(contents(&mut flash, B.location, B.size), vec![0xAA; B.size]);
+        }
+    }
+
+    #[test]
+    fn swapping_banks_across_devices() {
+        let (mut state, mut internal, mut external) = (
+            FakeFlash::uniform(2, 1024),
+            FakeFlash::uniform(6, BLOCK),
+            FakeFlash::uniform(6, BLOCK),
+        );
+        fill(&mut internal, A.location, A.size, 0x12);
+        fill(&mut external, B.location, B.size, 0x34);
+        let mut manager = Manager::load(store(&mut state)).unwrap();
+
+        manager.swap(&mut internal, &A, &mut external, &B, &SCRATCH).unwrap();
+
+        assert_eq!(contents(&mut internal, A.location, A.size), vec![0x34; A.size]);
+        assert_eq!(contents(&mut external, B.location, B.size), vec![0x12; B.size]);
+    }
+}
@@ -0,0 +1,387 @@
+//! Persistent circular log of variable length records, e.g. for event logs
+//! that must survive resets.
+//!
+//! Records are appended in order across every erase block of a flash
+//! (usually a `Partition`), and never straddle two blocks. When the current
+//! block runs out of space, the next one (wrapping around) is erased, which
+//! drops the oldest records.
+//!
+//! Every record carries a sequence number and a CRC. After a reset, the block
+//! holding the newest records is the one starting with the highest sequence
+//! number (compared as serial numbers, so they may wrap), and the end of the log is found by scanning it. Records torn by
+//! an interrupted write are skipped.
+use crate::{
+    hal::flash::{EraseBlock, EraseRegion, Geometry},
+    utilities::memory::{Address, Region},
+};
+use crc::crc32;
+
+pub const MAX_PAYLOAD_SIZE: usize = 256;
+
+/// Magic number, payload size (little endian `u16`), sequence number and
+/// CRC32 of everything in the record but the CRC itself (little endian `u32`).
+const RECORD_HEADER_SIZE: usize = 12;
+const MAX_RECORD_SIZE: usize = RECORD_HEADER_SIZE + MAX_PAYLOAD_SIZE;
+const RECORD_MAGIC: u16 = 0x10C5;
+
+/// Records are padded so they can be written on word aligned devices.
+const RECORD_ALIGNMENT: usize = 4;
+const ERASED: u8 = 0xFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    PayloadTooLong,
+    /// The buffer passed to `next_record` can't hold the record payload.
+    BufferTooSmall {
+        required: usize,
+    },
+    /// The flash has no erase blocks, or one too small for the largest record.
+    BlocksTooSmall,
+}
+
+/// A record read back from the log, whose payload was copied to a buffer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub sequence: u32,
+    pub size: usize,
+}
+
+/// Result of reading the log at a given address.
+enum Entry {
+    Valid(Record),
+    /// Nothing written past this point.
+    Erased,
+    /// Interrupted or corrupted write.
+    Corrupt,
+}
+
+pub struct Log<'a, F: EraseRegion + Geometry> {
+    flash: &'a mut F,
+    /// Index of the erase block holding the newest records.
+    block: usize,
+    /// Address following the newest record.
+    head: F::Address,
+    /// Sequence number of the next record.
+    sequence: u32,
+}
+
+impl<'a, F: EraseRegion + Geometry> Log<'a, F> {
+    /// Opens the log held in every erase block of a flash, recovering the
+    /// end of the log. A partially written block is closed, so appending
+    /// resumes from the next one.
+    pub fn mount(flash: &'a mut F) -> Result<Self, Error<F::Error>> {
+        let start = flash.erase_block(0).ok_or(Error::BlocksTooSmall)?.start;
+        if flash.erase_blocks().any(|block| block.size < MAX_RECORD_SIZE) {
+            return Err(Error::BlocksTooSmall);
+        }
+
+        let mut log = Self { flash, block: 0, head: start, sequence: 0 };
+        let mut buffer = [0u8; MAX_RECORD_SIZE];
+        let mut newest = None;
+        for index in 0..log.flash.erase_block_count() {
+            let block = log.block_at(index);
+            if let Entry::Valid(record) = log.read_entry(block.start, block, &mut buffer)? {
+                if newest.is_none_or(|(_, sequence)| is_after(record.sequence, sequence)) {
+                    newest = Some((index, record.sequence));
+                }
+            }
+        }
+
+        log.block = newest.map_or(0, |(index, _)| index);
+        log.find_head()?;
+        Ok(log)
+    }
+
+    /// Appends a record, erasing the oldest block if the log is full.
+    pub fn append(&mut self, payload: &[u8]) -> Result<u32, Error<F::Error>> {
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(Error::PayloadTooLong);
+        }
+
+        let size = record_size(payload.len());
+        let mut buffer = [ERASED; MAX_RECORD_SIZE];
+        buffer[..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        buffer[2..4].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        buffer[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        buffer[RECORD_HEADER_SIZE..][..payload.len()].copy_from_slice(payload);
+        let crc = record_crc(&buffer, payload.len());
+        buffer[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        // A record never straddles two blocks, so it must fit entirely in
+        // the current one.
+        if !fits(self.block_at(self.block), self.head, size) {
+            self.open_next_block()?;
+        }
+
+        // The header goes last, so an interrupted write never looks valid.
+        let (header, body) = buffer[..size].split_at(RECORD_HEADER_SIZE);
+        nb::block!(self.flash.write(self.head + RECORD_HEADER_SIZE, body)).map_err(Error::Flash)?;
+        nb::block!(self.flash.write(self.head, header)).map_err(Error::Flash)?;
+        self.head = self.head + size;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(self.sequence.wrapping_sub(1))
+    }
+
+    /// Iterates over the records, from oldest to newest.
+    pub fn records(&mut self) -> Records<'_, 'a, F> {
+        let count = self.flash.erase_block_count();
+        let first = (self.block + 1) % count;
+        let address = self.block_at(first).start;
+        Records { log: self, visited: 0, address }
+    }
+
+    /// Sequence number the next record will get.
+    pub fn next_sequence(&self) -> u32 { self.sequence }
+
+    fn block_at(&self, index: usize) -> EraseBlock<F::Address> {
+        // Indices are always taken modulo the block count.
+        self.flash.erase_block(index).expect("Erase block index out of range")
+    }
+
+    /// Moves the head past the newest record, and past the whole block if
+    /// the rest of it isn't cleanly erased.
+    fn find_head(&mut self) -> Result<(), Error<F::Error>> {
+        let block = self.block_at(self.block);
+        let mut buffer = [0u8; MAX_RECORD_SIZE];
+        self.head = block.start;
+        while let Entry::Valid(record) = self.read_entry(self.head, block, &mut buffer)? {
+            self.head = self.head + record_size(record.size);
+            self.sequence = record.sequence.wrapping_add(1);
+        }
+
+        let mut clean = true;
+        let mut chunk = [0u8; MAX_RECORD_SIZE];
+        let mut chunks = self.flash.chunks(self.head, block.end() - self.head, &mut chunk);
+        while let Some(chunk) = chunks.next_chunk() {
+            clean &= chunk.map_err(Error::Flash)?.iter().all(|b| *b == ERASED);
+        }
+        if !clean {
+            self.open_next_block()?;
+        }
+        Ok(())
+    }
+
+    fn open_next_block(&mut self) -> Result<(), Error<F::Error>> {
+        self.block = (self.block + 1) % self.flash.erase_block_count();
+        let block = self.block_at(self.block);
+        nb::block!(self.flash.erase_range(block.start, block.end())).map_err(Error::Flash)?;
+        self.head = block.start;
+        Ok(())
+    }
+
+    fn read_entry(
+        &mut self,
+        address: F::Address,
+        block: EraseBlock<F::Address>,
+        buffer: &mut [u8; MAX_RECORD_SIZE],
+    ) -> Result<Entry, Error<F::Error>> {
+        if !fits(block, address, RECORD_HEADER_SIZE) {
+            return Ok(Entry::Erased);
+        }
+        let header = &mut buffer[..RECORD_HEADER_SIZE];
+        nb::block!(self.flash.read(address, header)).map_err(Error::Flash)?;
+        if header.iter().all(|b| *b == ERASED) {
+            return Ok(Entry::Erased);
+        }
+
+        let magic = u16::from_le_bytes([header[0], header[1]]);
+        let size = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if (magic != RECORD_MAGIC)
+            || (size > MAX_PAYLOAD_SIZE)
+            || !fits(block, address, record_size(size))
+        {
+            return Ok(Entry::Corrupt);
+        }
+
+        let payload = &mut buffer[RECORD_HEADER_SIZE..][..size];
+        nb::block!(self.flash.read(address + RECORD_HEADER_SIZE, payload)).map_err(Error::Flash)?;
+        Ok(if record_crc(buffer, size) == crc {
+            Entry::Valid(Record { sequence, size })
+        } else {
+            Entry::Corrupt
+        })
+    }
+}
+
+/// Cursor over the records of a log, see `Log::records`.
+pub struct Records<'r, 'a, F: EraseRegion + Geometry> {
+    log: &'r mut Log<'a, F>,
+    /// Number of blocks fully read.
+    visited: usize,
+    address: F::Address,
+}
+
+impl<'r, 'a, F: EraseRegion + Geometry> Records<'r, 'a, F> {
+    /// Copies the payload of the next record into a buffer. Returns `None`
+    /// once the newest record has been read.
+    pub fn next_record(&mut self, payload: &mut [u8]) -> Option<Result<Record, Error<F::Error>>> {
+        let count = self.log.flash.erase_block_count();
+        let mut buffer = [0u8; MAX_RECORD_SIZE];
+        while self.visited < count {
+            let block = self.log.block_at((self.log.block + 1 + self.visited) % count);
+            let record = match self.log.read_entry(self.address, block, &mut buffer) {
+                Err(error) => return Some(Err(error)),
+                Ok(Entry::Valid(record)) => record,
+                Ok(_) => {
+                    self.visited += 1;
+                    let next = (self.log.block + 1 + self.visited) % count;
+                    self.address = self.log.block_at(next).start;
+                    continue;
+                }
+            };
+
+            self.address = self.address + record_size(record.size);
+            if payload.len() < record.size {
+                return Some(Err(Error::BufferTooSmall { required: record.size }));
+            }
+            payload[..record.size].copy_from_slice(&buffer[RECORD_HEADER_SIZE..][..record.size]);
+            return Some(Ok(record));
+        }
+        None
+    }
+}
+
+/// Whether `size` bytes starting at `address` lie within a region.
+fn fits<A: Address>(region: impl Region<A>, address: A, size: usize) -> bool {
+    region.contains(address) && region.contains(address + size - 1)
+}
+
+/// Whether sequence number `a` was given out after `b`, as serial numbers
+/// that wrap around.
+fn is_after(a: u32, b: u32) -> bool { a.wrapping_sub(b) as i32 > 0 }
+
+fn record_size(payload_size: usize) -> usize {
+    (RECORD_HEADER_SIZE + payload_size).div_ceil(RECORD_ALIGNMENT) * RECORD_ALIGNMENT
+}
+
+fn record_crc(buffer: &[u8], payload_size: usize) -> u32 {
+    let payload = &buffer[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload_size];
+    crc32::update(crc32::checksum_ieee(&buffer[..8]), &crc32::IEEE_TABLE, payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::doubles::{
+        faulty_flash::{Fault, FaultyFlash, Tear},
+        flash::FakeFlash,
+    };
+
+    const BLOCK_SIZE: usize = 512;
+
+    fn read_all<F: EraseRegion + Geometry>(log: &mut Log<F>) -> Vec<(u32, Vec<u8>)> {
+        let mut records = log.records();
+        let mut payload = [0u8; MAX_PAYLOAD_SIZE];
+        let mut all = Vec::new();
+        while let Some(record) = records.next_record(&mut payload) {
+            let record = record.map_err(|_| ()).unwrap();
+            all.push((record.sequence, payload[..record.size].to_vec()));
+        }
+        all
+    }
+
+    #[test]
+    fn appending_and_reading_records_in_order() {
+        // Given
+        let mut flash = FakeFlash::uniform(3, BLOCK_SIZE);
+        let mut log = Log::mount(&mut flash).unwrap();
+
+        // When
+        log.append(b"boot").unwrap();
+        log.append(b"").unwrap();
+        log.append(&[0xAB; 100]).unwrap();
+
+        // Then
+        let expected = vec![(0, b"boot".to_vec()), (1, vec![]), (2, vec![0xAB; 100])];
+        assert_eq!(read_all(&mut log), expected);
+    }
+
+    #[test]
+    fn records_and_sequence_numbers_persist_across_mounts() {
+        let mut flash = FakeFlash::uniform(3, BLOCK_SIZE);
+        Log::mount(&mut flash).unwrap().append(b"first").unwrap();
+
+        let mut log = Log::mount(&mut flash).unwrap();
+        let sequence = log.append(b"second").unwrap();
+
+        assert_eq!(sequence, 1);
+        assert_eq!(read_all(&mut log), vec![(0, b"first".to_vec()), (1, b"second".to_vec())]);
+    }
+
+    #[test]
+    fn full_logs_drop_their_oldest_records() {
+        // Given
+        let mut flash = FakeFlash::uniform(3, BLOCK_SIZE);
+        let mut log = Log::mount(&mut flash).unwrap();
+
+        // When
+        for i in 0..200u32 {
+            log.append(&i.to_le_bytes()).unwrap();
+        }
+        let records = read_all(&mut log);
+        let mut remounted = Log::mount(&mut flash).unwrap();
+
+        // Then
+        assert_eq!(records.last(), Some(&(199, 199u32.to_le_bytes().to_vec())));
+        assert!(records.len() < 200);
+        assert!(records.windows(2).all(|pair| pair[1].0 == pair[0].0 + 1));
+        assert!(records.iter().all(|(sequence, payload)| *payload == sequence.to_le_bytes()));
+        assert_eq!(read_all(&mut remounted), records);
+        assert!(flash.erase_cycles().iter().all(|cycles| *cycles > 1));
+    }
+
+    #[test]
+    fn mounting_finds_the_newest_block_after_sequence_numbers_wrap() {
+        // Given
+        let mut flash = FakeFlash::uniform(3, BLOCK_SIZE);
+        let mut log = Log::mount(&mut flash).unwrap();
+        log.sequence = u32::MAX - 3;
+        for _ in 0..6 {
+            log.append(&[0xAB; 100]).unwrap();
+        }
+
+        // When
+        let mut log = Log::mount(&mut flash).unwrap();
+        let sequence = log.append(b"after").unwrap();
+
+        // Then
+        assert_eq!(sequence, 2);
+        let sequences: Vec<_> = read_all(&mut log).into_iter().map(|(s, _)| s).collect();
+        assert_eq!(sequences, vec![u32::MAX - 3, u32::MAX - 2, u32::MAX - 1, u32::MAX, 0, 1, 2]);
+    }
+
+    #[test]
+    fn interrupted_appends_are_skipped() {
+        // Given
+        let mut flash = FaultyFlash::new(FakeFlash::uniform(3, BLOCK_SIZE));
+        Log::mount(&mut flash).unwrap().append(b"kept").unwrap();
+        let tear = Tear::Randomised { seed: 5 };
+        flash.inject(Fault::PowerLossOnWrite { write: 1, programmed: 3, tear });
+
+        // When
+        assert!(Log::mount(&mut flash).unwrap().append(b"torn").is_err());
+        flash.restore_power();
+        let mut log = Log::mount(&mut flash).unwrap();
+        log.append(b"after").unwrap();
+
+        // Then
+        assert_eq!(read_all(&mut log), vec![(0, b"kept".to_vec()), (1, b"after".to_vec())]);
+    }
+
+    #[test]
+    fn oversized_payloads_and_small_buffers_are_rejected() {
+        let mut flash = FakeFlash::uniform(3, BLOCK_SIZE);
+        let mut log = Log::mount(&mut flash).unwrap();
+        log.append(&[0; 10]).unwrap();
+
+        assert_eq!(log.append(&[0; MAX_PAYLOAD_SIZE + 1]), Err(Error::PayloadTooLong));
+        let mut small = [0u8; 4];
+        let result = log.records().next_record(&mut small);
+        assert_eq!(result, Some(Err(Error::BufferTooSmall { required: 10 })));
+        let mut tiny = FakeFlash::uniform(2, 64);
+        assert_eq!(Log::mount(&mut tiny).err(), Some(Error::BlocksTooSmall));
+    }
+}
@@ -0,0 +1,240 @@
+//! Flash to flash copy and comparison, e.g. to install an image from external
+//! to internal flash and check the result.
+//!
+//! Both flashes are streamed through small stack buffers, and may use
+//! different address types, so positions within a transfer are reported as
+//! byte offsets from its start. A CRC32 (IEEE) of the source bytes can be
+//! accumulated on the way, to check an image against its header without
+//! reading it again.
+use crate::hal::flash::{ReadWrite, CHUNK_SIZE};
+use crc::crc32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<S, D> {
+    Source(S),
+    Destination(D),
+    /// The range is reversed, or runs past the end of either flash.
+    OutOfRange,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    /// The contents first differ at this offset from the start of the range.
+    Mismatch {
+        offset: usize,
+    },
+}
+
+/// Copies a range of one flash to another, from an address onwards. If
+/// given, `crc` is updated with the copied bytes (start from 0 for a plain
+/// CRC32 of the range).
+pub fn copy<S: ReadWrite, D: ReadWrite>(
+    source: &mut S,
+    range: (S::Address, S::Address),
+    destination: &mut D,
+    target: D::Address,
+    crc: Option<&mut u32>,
+) -> Result<(), Error<S::Error, D::Error>> {
+    copy_between(&mut Between(source, destination), range, target, crc)
+}
+
+/// Compares a range of one flash with another, from an address onwards. If
+/// given, `crc` is updated with the source bytes compared before the first
+/// mismatch.
+pub fn compare<S: ReadWrite, D: ReadWrite>(
+    source: &mut S,
+    range: (S::Address, S::Address),
+    destination: &mut D,
+    target: D::Address,
+    crc: Option<&mut u32>,
+) -> Result<Comparison, Error<S::Error, D::Error>> {
+    compare_between(&mut Between(source, destination), range, target, crc)
+}
+
+/// Source and destination of a transfer, which may be the same device.
+pub(crate) trait Endpoints {
+    type Source: ReadWrite;
+    type Destination: ReadWrite;
+    fn source(&mut self) -> &mut Self::Source;
+    fn destination(&mut self) -> &mut Self::Destination;
+}
+
+pub(crate) struct Between<'a, S, D>(pub &'a mut S, pub &'a mut D);
+pub(crate) struct Within<'a, F>(pub &'a mut F);
+
+impl<'a, S: ReadWrite, D: ReadWrite> Endpoints for Between<'a, S, D> {
+    type Source = S;
+    type Destination = D;
+    fn source(&mut self) -> &mut S { self.0 }
+    fn destination(&mut self) -> &mut D { self.1 }
+}
+
+impl<'a, F: ReadWrite> Endpoints for Within<'a, F> {
+    type Source = F;
+    type Destination = F;
+    fn source(&mut self) -> &mut F { self.0 }
+    fn destination(&mut self) -> &mut F { self.0 }
+}
+
+pub(crate) type EndpointsError<E> = Error<
+    <<E as Endpoints>::Source as ReadWrite>::Error,
+    <<E as Endpoints>::Destination as ReadWrite>::Error,
+>;
+type SourceAddress<E> = <<E as Endpoints>::Source as ReadWrite>::Address;
+type DestinationAddress<E> = <<E as Endpoints>::Destination as ReadWrite>::Address;
+
+/// Like `copy`, for any endpoints.
+pub(crate) fn copy_between<E: Endpoints>(
+    endpoints: &mut E,
+    range: (SourceAddress<E>, SourceAddress<E>),
+    target: DestinationAddress<E>,
+    mut crc: Option<&mut u32>,
+) -> Result<(), EndpointsError<E>> {
+    let size = checked_size(endpoints, range, target)?;
+    let mut buffer = [0u8; CHUNK_SIZE];
+    for offset in (0..size).step_by(CHUNK_SIZE) {
+        let chunk = &mut buffer[..CHUNK_SIZE.min(size - offset)];
+        nb::block!(endpoints.source().read(range.0 + offset, chunk)).map_err(Error::Source)?;
+        nb::block!(endpoints.destination().write(target + offset, chunk))
+            .map_err(Error::Destination)?;
+        if let Some(crc) = crc.as_deref_mut() {
+            *crc = crc32::update(*crc, &crc32::IEEE_TABLE, chunk);
+        }
+    }
+    Ok(())
+}
+
+/// Like `compare`, for any endpoints.
+pub(crate) fn compare_between<E: Endpoints>(
+    endpoints: &mut E,
+    range: (SourceAddress<E>, SourceAddress<E>),
+    target: DestinationAddress<E>,
+    mut crc: Option<&mut u32>,
+) -> Result<Comparison, EndpointsError<E>> {
+    let size = checked_size(endpoints, range, target)?;
+    let (mut buffer, mut other) = ([0u8; CHUNK_SIZE], [0u8; CHUNK_SIZE]);
+    for offset in (0..size).step_by(CHUNK_SIZE) {
+        let length = CHUNK_SIZE.min(size - offset);
+        let (chunk, other) = (&mut buffer[..length], &mut other[..length]);
+        nb::block!(endpoints.source().read(range.0 + offset, chunk)).map_err(Error::Source)?;
+        nb::block!(endpoints.destination().read(target + offset, other))
+            .map_err(Error::Destination)?;
+        let mismatch = chunk.iter().zip(other.iter()).position(|(a, b)| a != b);
+        if let Some(crc) = crc.as_deref_mut() {
+            let compared = &chunk[..mismatch.unwrap_or(length)];
+            *crc = crc32::update(*crc, &crc32::IEEE_TABLE, compared);
+        }
+        if let Some(index) = mismatch {
+            return Ok(Comparison::Mismatch { offset: offset + index });
+        }
+    }
+    Ok(Comparison::Equal)
+}
+
+/// Size of a range, if it fits in both flashes.
+fn checked_size<E: Endpoints>(
+    endpoints: &mut E,
+    (start, end): (SourceAddress<E>, SourceAddress<E>),
+    target: DestinationAddress<E>,
+) -> Result<usize, EndpointsError<E>> {
+    if start > end {
+        return Err(Error::OutOfRange);
+    }
+    let (source_start, source_end) = endpoints.source().range();
+    let (destination_start, destination_end) = endpoints.destination().range();
+    let size = end - start;
+    let fits = (start >= source_start)
+        && (end <= source_end)
+        && (target >= destination_start)
+        && (target + size <= destination_end);
+    if fits {
+        Ok(size)
+    } else {
+        Err(Error::OutOfRange)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        hal::doubles::flash::*,
+        utilities::partition::{Offset, Partition},
+    };
+
+    const LAYOUT: &[(usize, usize)] = &[(4, 1024)];
+
+    fn source_with(image: &[u8]) -> FakeFlash {
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(Address(0x100), image).unwrap();
+        flash
+    }
+
+    fn image() -> Vec<u8> { (0..1000).map(|i| (i * 13) as u8).collect() }
+
+    #[test]
+    fn copying_between_flashes_with_different_address_types() {
+        // Given
+        let image = image();
+        let mut source = source_with(&image);
+        let mut internal = FakeFlash::with_layout(Address(0x8000), LAYOUT, ProgramMode::Strict);
+        let mut destination = Partition::new(&mut internal, Address(0x8400), 2048).unwrap();
+        let range = (Address(0x100), Address(0x100) + image.len());
+        let mut crc = 0;
+
+        // When
+        copy(&mut source, range, &mut destination, Offset(10), Some(&mut crc)).unwrap();
+
+        // Then
+        assert_eq!(crc, crc32::checksum_ieee(&image));
+        assert_eq!(
+            compare(&mut source, range, &mut destination, Offset(10), None),
+            Ok(Comparison::Equal)
+        );
+        assert_eq!(contents(&mut internal, Address(0x8400 + 10), image.len()), image);
+    }
+
+    #[test]
+    fn comparisons_report_the_first_mismatch() {
+        // Given
+        let image = image();
+        let mut source = source_with(&image);
+        let mut destination = FakeFlash::with_layout(Address(0), LAYOUT, ProgramMode::Strict);
+        let mut altered = image.clone();
+        altered[700] ^= 0x01;
+        altered[900] ^= 0x01;
+        destination.write(Address(0), &altered).unwrap();
+        let range = (Address(0x100), Address(0x100) + image.len());
+        let mut crc = 0;
+
+        // When
+        let result = compare(&mut source, range, &mut destination, Address(0), Some(&mut crc));
+
+        // Then
+        assert_eq!(result, Ok(Comparison::Mismatch { offset: 700 }));
+        assert_eq!(crc, crc32::checksum_ieee(&image[..700]));
+    }
+
+    #[test]
+    fn ranges_must_fit_in_both_flashes() {
+        let mut source = source_with(&image());
+        let mut destination = FakeFlash::with_layout(Address(0), LAYOUT, ProgramMode::Strict);
+        let reversed = (Address(0x200), Address(0x100));
+        let oversized = (Address(0), Address(0x1001));
+
+        assert_eq!(
+            copy(&mut source, reversed, &mut destination, Address(0), None),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(
+            copy(&mut source, oversized, &mut destination, Address(0), None),
+            Err(Error::OutOfRange)
+        );
+        destination.write(Address(0), &[0x00]).unwrap();
+        assert_eq!(
+            copy(&mut source, (Address(0x101), Address(0x110)), &mut destination, Address(0), None),
+            Err(Error::Destination(FakeFlashError::ProgramWithoutErase(Address(0))))
+        );
+    }
+}
@@ -0,0 +1,570 @@
+//! Minimal power-safe filesystem, generic over any flash with range erase,
+//! e.g. a `Partition` of an external QSPI flash.
+//!
+//! Every file lives in a contiguous run of erase blocks, starting with a
+//! header that holds its name, size and generation. Files are never modified
+//! in place: writing a file erases a free run of blocks, programs the data,
+//! and only then programs the header. The header is the commit point, so an
+//! interrupted write leaves the previous version of the file untouched.
+//!
+//! A newer generation of a file supersedes the older ones, which are then
+//! marked as deleted by clearing a word of their header. Generations are
+//! compared as serial numbers, so they may wrap around. Deleted files and
+//! uncommitted writes don't hold a valid header, so their blocks are free.
+//! There is no directory held in RAM, so every operation scans the headers.
+//!
+//! Headers are made of whole words, and file data is written a word at a
+//! time, so devices that program a word at a time are supported.
+use crate::hal::flash::{EraseBlock, EraseRegion, Geometry};
+use crc::crc32;
+
+pub const MAX_NAME_SIZE: usize = 32;
+
+/// Magic number, generation, file size, name size (little endian `u32`),
+/// name, CRC32 of all of the above, and a deletion marker that stays erased
+/// until the file is deleted or superseded.
+const HEADER_SIZE: usize = 56;
+const CHECKED_SIZE: usize = 48;
+const COMMITTED_SIZE: usize = CHECKED_SIZE + 4;
+const HEADER_MAGIC: u32 = 0xF11E_5A5E;
+const LIVE: [u8; 4] = [0xFF; 4];
+const ERASED: u8 = 0xFF;
+/// Largest supported write alignment, that of the header fields.
+const WORD_SIZE: usize = 4;
+const DELETED: [u8; 4] = [0x00; 4];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    EmptyName,
+    NameTooLong,
+    NotFound,
+    /// No run of free erase blocks is large enough for the file.
+    NoSpace,
+    /// A write went past the size requested when creating the file.
+    FileTooLarge,
+    /// The flash has no erase blocks, or one too small for a file header.
+    BlocksTooSmall,
+    /// The flash can't be programmed a word at a time.
+    UnsupportedAlignment,
+}
+
+/// Handle to a committed file, valid until the file is written or deleted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct File<A> {
+    start: A,
+    size: usize,
+}
+
+impl<A> File<A> {
+    pub fn size(&self) -> usize { self.size }
+}
+
+/// Name and size of a file, as listed by `Filesystem::files`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    name: [u8; MAX_NAME_SIZE],
+    name_size: usize,
+    pub size: usize,
+}
+
+impl Metadata {
+    pub fn name(&self) -> &[u8] { &self.name[..self.name_size] }
+}
+
+#[derive(Copy, Clone)]
+struct Header {
+    generation: u32,
+    metadata: Metadata,
+}
+
+pub struct Filesystem<'a, F: EraseRegion + Geometry> {
+    flash: &'a mut F,
+    /// Generation of the next committed file.
+    generation: u32,
+    /// Block from which to look for free space, so wear is spread across
+    /// the whole flash.
+    cursor: usize,
+}
+
+impl<'a, F: EraseRegion + Geometry> Filesystem<'a, F> {
+    /// Opens the filesystem spanning a whole flash, finishing any overwrite
+    /// interrupted before the previous version was marked as deleted.
+    /// Erased memory is a valid, empty filesystem.
+    pub fn mount(flash: &'a mut F) -> Result<Self, Error<F::Error>> {
+        if flash.erase_block(0).is_none() || flash.erase_blocks().any(|b| b.size < HEADER_SIZE) {
+            return Err(Error::BlocksTooSmall);
+        }
+        if !WORD_SIZE.is_multiple_of(flash.write_alignment().max(1)) {
+            return Err(Error::UnsupportedAlignment);
+        }
+
+        let mut filesystem = Self { flash, generation: 0, cursor: 0 };
+        let mut newest: Option<Header> = None;
+        let mut index = 0;
+        while index < filesystem.flash.erase_block_count() {
+            let (header, next) = filesystem.extent_at(index)?;
+            if let Some(header) = header {
+                if newest.is_none_or(|newest| is_newer(header.generation, newest.generation)) {
+                    newest = Some(header);
+                    filesystem.cursor = next;
+                }
+            }
+            index = next;
+        }
+
+        if let Some(newest) = newest {
+            filesystem.generation = newest.generation.wrapping_add(1);
+            // Only the last commit can have been interrupted after a reset,
+            // and looking the file up supersedes its previous version.
+            filesystem.find(newest.metadata.name())?;
+        }
+        Ok(filesystem)
+    }
+
+    /// Erases every file.
+    pub fn format(&mut self) -> Result<(), Error<F::Error>> {
+        nb::block!(self.flash.erase()).map_err(Error::Flash)?;
+        self.generation = 0;
+        self.cursor = 0;
+        Ok(())
+    }
+
+    pub fn open(&mut self, name: &[u8]) -> Result<File<F::Address>, Error<F::Error>> {
+        check_name(name)?;
+        let (index, header) = self.find(name)?.ok_or(Error::NotFound)?;
+        Ok(File { start: self.block_at(index).start, size: header.metadata.size })
+    }
+
+    /// Reads a file from an offset onwards, returning the number of bytes
+    /// read, which is smaller than the buffer at the end of the file.
+    pub fn read(
+        &mut self,
+        file: &File<F::Address>,
+        offset: usize,
+        bytes: &mut [u8],
+    ) -> Result<usize, Error<F::Error>> {
+        let size = bytes.len().min(file.size.saturating_sub(offset));
+        let address = file.start + HEADER_SIZE + offset;
+        nb::block!(self.flash.read(address, &mut bytes[..size])).map_err(Error::Flash)?;
+        Ok(size)
+    }
+
+    /// Writes (or replaces) a whole file at once.
+    pub fn write(&mut self, name: &[u8], data: &[u8]) -> Result<(), Error<F::Error>> {
+        let mut writer = self.create(name, data.len())?;
+        writer.write(data)?;
+        writer.commit()
+    }
+
+    /// Starts writing (or replacing) a file of up to `capacity` bytes, e.g.
+    /// when streaming an image too large to hold in RAM. The file only
+    /// becomes visible once committed; until then, any previous version
+    /// remains readable.
+    pub fn create(
+        &mut self,
+        name: &[u8],
+        capacity: usize,
+    ) -> Result<Writer<'_, 'a, F>, Error<F::Error>> {
+        check_name(name)?;
+        let index = self.allocate(capacity)?;
+        let start = self.block_at(index).start;
+        let mut metadata = Metadata { name: [0u8; MAX_NAME_SIZE], name_size: name.len(), size: 0 };
+        metadata.name[..name.len()].copy_from_slice(name);
+        let tail = [ERASED; WORD_SIZE];
+        Ok(Writer { filesystem: self, index, start, capacity, metadata, tail })
+    }
+
+    pub fn delete(&mut self, name: &[u8]) -> Result<(), Error<F::Error>> {
+        check_name(name)?;
+        let (index, _) = self.find(name)?.ok_or(Error::NotFound)?;
+        self.mark_deleted(index)
+    }
+
+    /// Iterates over the committed files, in address order.
+    pub fn files(&mut self) -> Files<'_, 'a, F> { Files { filesystem: self, index: 0 } }
+
+    fn block_at(&self, index: usize) -> EraseBlock<F::Address> {
+        // Indices always come from a scan bounded by the block count.
+        self.flash.erase_block(index).expect("Erase block index out of range")
+    }
+
+    /// Reads the header at the start of a block, returning it if the block
+    /// starts a live file, and the index of the block following the file.
+    fn extent_at(&mut self, index: usize) -> Result<(Option<Header>, usize), Error<F::Error>> {
+        let start = self.block_at(index).start;
+        let mut bytes = [0u8; HEADER_SIZE];
+        nb::block!(self.flash.read(start, &mut bytes)).map_err(Error::Flash)?;
+
+        let field =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let (size, name_size) = (field(8) as usize, field(12) as usize);
+        let valid = (field(0) == HEADER_MAGIC)
+            && (crc32::checksum_ieee(&bytes[..CHECKED_SIZE]) == field(CHECKED_SIZE))
+            && (bytes[COMMITTED_SIZE..] == LIVE)
+            && (1..=MAX_NAME_SIZE).contains(&name_size)
+            && (size <= self.flash.range().1 - (start + HEADER_SIZE));
+        if !valid {
+            return Ok((None, index + 1));
+        }
+
+        let mut metadata = Metadata { name: [0u8; MAX_NAME_SIZE], name_size, size };
+        metadata.name.copy_from_slice(&bytes[16..CHECKED_SIZE]);
+        let end = start + HEADER_SIZE + size;
+        let mut next = index + 1;
+        while next < self.flash.erase_block_count() && self.block_at(next).start < end {
+            next += 1;
+        }
+        Ok((Some(Header { generation: field(4), metadata }), next))
+    }
+
+    /// Index and header of the latest generation of a file. Older versions
+    /// left behind by an interrupted commit are marked as deleted on the way.
+    fn find(&mut self, name: &[u8]) -> Result<Option<(usize, Header)>, Error<F::Error>> {
+        let mut found: Option<(usize, Header)> = None;
+        let mut index = 0;
+        while index < self.flash.erase_block_count() {
+            let (header, next) = self.extent_at(index)?;
+            if let Some(header) = header.filter(|h| h.metadata.name() == name) {
+                match found {
+                    Some((_, latest)) if !is_newer(header.generation, latest.generation) => {
+                        self.mark_deleted(index)?;
+                    }
+                    Some((previous, _)) => {
+                        self.mark_deleted(previous)?;
+                        found = Some((index, header));
+                    }
+                    None => found = Some((index, header)),
+                }
+            }
+            index = next;
+        }
+        Ok(found)
+    }
+
+    /// Finds and erases the first run of free blocks that can hold a file,
+    /// from the cursor onwards and wrapping around, returning the index of
+    /// its first block.
+    fn allocate(&mut self, capacity: usize) -> Result<usize, Error<F::Error>> {
+        let (mut run, mut found) = (None, None);
+        let mut index = 0;
+        while index < self.flash.erase_block_count() {
+            let (header, next) = self.extent_at(index)?;
+            if header.is_some() {
+                run = None;
+            } else {
+                let first = *run.get_or_insert(index);
+                if self.block_at(first).start + HEADER_SIZE + capacity <= self.block_at(index).end()
+                {
+                    if first >= self.cursor {
+                        found = Some((first, index));
+                        break;
+                    }
+                    found = found.or(Some((first, index)));
+                    run = None;
+                }
+            }
+            index = next;
+        }
+
+        let (first, last) = found.ok_or(Error::NoSpace)?;
+        let (start, end) = (self.block_at(first).start, self.block_at(last).end());
+        nb::block!(self.flash.erase_range(start, end)).map_err(Error::Flash)?;
+        self.cursor = last + 1;
+        Ok(first)
+    }
+
+    fn mark_deleted(&mut self, index: usize) -> Result<(), Error<F::Error>> {
+        let address = self.block_at(index).start + COMMITTED_SIZE;
+        nb::block!(self.flash.write(address, &DELETED)).map_err(Error::Flash)
+    }
+}
+
+/// File being written, see `Filesystem::create`.
+pub struct Writer<'f, 'a, F: EraseRegion + Geometry> {
+    filesystem: &'f mut Filesystem<'a, F>,
+    index: usize,
+    start: F::Address,
+    capacity: usize,
+    metadata: Metadata,
+    /// Trailing partial word, written once completed or on commit.
+    tail: [u8; WORD_SIZE],
+}
+
+impl<'f, 'a, F: EraseRegion + Geometry> Writer<'f, 'a, F> {
+    /// Appends data to the file. On word aligned devices, a trailing
+    /// partial word is held back until the next append or the commit.
+    pub fn write(&mut self, mut bytes: &[u8]) -> Result<(), Error<F::Error>> {
+        if self.metadata.size + bytes.len() > self.capacity {
+            return Err(Error::FileTooLarge);
+        }
+
+        let alignment = self.filesystem.flash.write_alignment().max(1);
+        let buffered = self.metadata.size % alignment;
+        if buffered > 0 {
+            let taken = (alignment - buffered).min(bytes.len());
+            self.tail[buffered..buffered + taken].copy_from_slice(&bytes[..taken]);
+            self.metadata.size += taken;
+            bytes = &bytes[taken..];
+            if buffered + taken == alignment {
+                self.write_tail(self.metadata.size - alignment, alignment)?;
+            }
+        }
+
+        let whole = bytes.len() - bytes.len() % alignment;
+        if whole > 0 {
+            let address = self.start + HEADER_SIZE + self.metadata.size;
+            nb::block!(self.filesystem.flash.write(address, &bytes[..whole]))
+                .map_err(Error::Flash)?;
+            self.metadata.size += whole;
+        }
+        let rest = &bytes[whole..];
+        self.tail[..rest.len()].copy_from_slice(rest);
+        self.metadata.size += rest.len();
+        Ok(())
+    }
+
+    /// Makes the file visible, replacing any previous version.
+    pub fn commit(mut self) -> Result<(), Error<F::Error>> {
+        let alignment = self.filesystem.flash.write_alignment().max(1);
+        let buffered = self.metadata.size % alignment;
+        if buffered > 0 {
+            self.tail[buffered..].fill(ERASED);
+            self.write_tail(self.metadata.size - buffered, alignment)?;
+        }
+
+        let previous = self.filesystem.find(self.metadata.name())?;
+        let generation = self.filesystem.generation;
+
+        let mut bytes = [0u8; COMMITTED_SIZE];
+        bytes[0..4].copy_from_slice(&HEADER_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&generation.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(self.metadata.size as u32).to_le_bytes());
+        bytes[12..16].copy_from_slice(&(self.metadata.name_size as u32).to_le_bytes());
+        bytes[16..CHECKED_SIZE].copy_from_slice(&self.metadata.name);
+        let crc = crc32::checksum_ieee(&bytes[..CHECKED_SIZE]);
+        bytes[CHECKED_SIZE..].copy_from_slice(&crc.to_le_bytes());
+        nb::block!(self.filesystem.flash.write(self.start, &bytes)).map_err(Error::Flash)?;
+        self.filesystem.generation = generation.wrapping_add(1);
+
+        // An interruption here leaves both versions committed, which the
+        // next mount resolves in favour of the newest one.
+        match previous {
+            Some((index, _)) if index != self.index => self.filesystem.mark_deleted(index),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the buffered partial word, completed or padded, at an offset.
+    fn write_tail(&mut self, offset: usize, alignment: usize) -> Result<(), Error<F::Error>> {
+        let address = self.start + HEADER_SIZE + offset;
+        nb::block!(self.filesystem.flash.write(address, &self.tail[..alignment]))
+            .map_err(Error::Flash)
+    }
+}
+
+/// Cursor over the committed files, see `Filesystem::files`.
+pub struct Files<'f, 'a, F: EraseRegion + Geometry> {
+    filesystem: &'f mut Filesystem<'a, F>,
+    index: usize,
+}
+
+impl<'f, 'a, F: EraseRegion + Geometry> Files<'f, 'a, F> {
+    pub fn next_file(&mut self) -> Option<Result<Metadata, Error<F::Error>>> {
+        while self.index < self.filesystem.flash.erase_block_count() {
+            let (header, next) = match self.filesystem.extent_at(self.index) {
+                Ok(extent) => extent,
+                Err(error) => return Some(Err(error)),
+            };
+            self.index = next;
+            if let Some(header) = header {
+                // Superseded versions linger only until the next mount.
+                match self.filesystem.find(header.metadata.name()) {
+                    Err(error) => return Some(Err(error)),
+                    Ok(Some((_, latest))) if latest.generation != header.generation => continue,
+                    Ok(_) => return Some(Ok(header.metadata)),
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Whether generation `a` was committed after `b`, as serial numbers that
+/// wrap around.
+fn is_newer(a: u32, b: u32) -> bool { a.wrapping_sub(b) as i32 > 0 }
+
+fn check_name<E>(name: &[u8]) -> Result<(), Error<E>> {
+    if name.is_empty() {
+        Err(Error::EmptyName)
+    } else if name.len() > MAX_NAME_SIZE {
+        Err(Error::NameTooLong)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::doubles::{
+        faulty_flash::{Fault, FaultyFlash, Tear},
+        flash::FakeFlash,
+    };
+
+    const BLOCK_SIZE: usize = 256;
+
+    fn contents<F: EraseRegion + Geometry>(fs: &mut Filesystem<F>, name: &[u8]) -> Option<Vec<u8>> {
+        let file = match fs.open(name) {
+            Err(Error::NotFound) => return None,
+            file => file.map_err(|_| ()).unwrap(),
+        };
+        let mut bytes = vec![0u8; file.size() + 1];
+        let size = fs.read(&file, 0, &mut bytes).map_err(|_| ()).unwrap();
+        Some(bytes[..size].to_vec())
+    }
+
+    fn names<F: EraseRegion + Geometry>(fs: &mut Filesystem<F>) -> Vec<Vec<u8>> {
+        let mut files = fs.files();
+        let mut names = Vec::new();
+        while let Some(metadata) = files.next_file() {
+            names.push(metadata.map_err(|_| ()).unwrap().name().to_vec());
+        }
+        names
+    }
+
+    #[test]
+    fn writing_reading_listing_and_deleting_files() {
+        // Given
+        let mut flash = FakeFlash::uniform(8, BLOCK_SIZE);
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+        let image: Vec<u8> = (0..600).map(|i| i as u8).collect();
+
+        // When
+        fs.write(b"config", b"baud=115200").unwrap();
+        fs.write(b"image.bin", &image).unwrap();
+        fs.write(b"empty", &[]).unwrap();
+        fs.delete(b"config").unwrap();
+
+        // Then
+        assert_eq!(contents(&mut fs, b"image.bin"), Some(image.clone()));
+        assert_eq!(contents(&mut fs, b"empty"), Some(vec![]));
+        assert_eq!(contents(&mut fs, b"config"), None);
+        assert_eq!(names(&mut fs), vec![b"image.bin".to_vec(), b"empty".to_vec()]);
+        let file = fs.open(b"image.bin").unwrap();
+        let mut tail = [0u8; 8];
+        assert_eq!(fs.read(&file, 596, &mut tail), Ok(4));
+        assert_eq!(tail[..4], image[596..]);
+    }
+
+    #[test]
+    fn files_are_replaced_and_persist_across_mounts() {
+        // Given
+        let mut flash = FakeFlash::uniform(8, BLOCK_SIZE);
+        Filesystem::mount(&mut flash).unwrap().write(b"log", b"first").unwrap();
+
+        // When
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+        let mut writer = fs.create(b"log", 300).unwrap();
+        writer.write(b"second, ").unwrap();
+        writer.write(b"streamed").unwrap();
+        writer.commit().unwrap();
+
+        // Then
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+        assert_eq!(contents(&mut fs, b"log"), Some(b"second, streamed".to_vec()));
+        assert_eq!(names(&mut fs), vec![b"log".to_vec()]);
+    }
+
+    #[test]
+    fn odd_sized_appends_are_written_a_word_at_a_time_on_aligned_devices() {
+        // Given
+        let mut flash = FakeFlash::uniform(8, BLOCK_SIZE);
+        flash.set_write_alignment(4);
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+        fs.write(b"old", b"x").unwrap();
+
+        // When
+        let mut writer = fs.create(b"log", 300).unwrap();
+        for chunk in [&b"odd"[..], b"ly ", b"s", b"ized, ", b"streamed"] {
+            writer.write(chunk).unwrap();
+        }
+        writer.commit().unwrap();
+        fs.delete(b"old").unwrap();
+
+        // Then
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+        assert_eq!(contents(&mut fs, b"log"), Some(b"oddly sized, streamed".to_vec()));
+        let mut doubleword = FakeFlash::uniform(8, BLOCK_SIZE);
+        doubleword.set_write_alignment(8);
+        assert!(matches!(Filesystem::mount(&mut doubleword), Err(Error::UnsupportedAlignment)));
+    }
+
+    #[test]
+    fn interrupted_writes_keep_the_previous_version() {
+        for write in 0..3 {
+            // Given
+            let mut flash = FaultyFlash::new(FakeFlash::uniform(8, BLOCK_SIZE));
+            Filesystem::mount(&mut flash).unwrap().write(b"settings", b"old").unwrap();
+            let tear = Tear::Randomised { seed: 7 };
+            flash.inject(Fault::PowerLossOnWrite { write, programmed: 2, tear });
+
+            // When
+            let result = Filesystem::mount(&mut flash).unwrap().write(b"settings", b"new");
+            flash.restore_power();
+            let mut fs = Filesystem::mount(&mut flash).unwrap();
+
+            // Then
+            let expected = if write < 2 { b"old" } else { b"new" };
+            assert!(result.is_err());
+            assert_eq!(contents(&mut fs, b"settings"), Some(expected.to_vec()));
+            assert_eq!(names(&mut fs), vec![b"settings".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn generations_wrap_around_across_interrupted_writes() {
+        // Given
+        let mut flash = FaultyFlash::new(FakeFlash::uniform(8, BLOCK_SIZE));
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+        fs.generation = u32::MAX;
+        fs.write(b"settings", b"old").unwrap();
+        flash.inject(Fault::WriteError { write: 2 });
+
+        // When
+        let result = Filesystem::mount(&mut flash).unwrap().write(b"settings", b"new");
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+
+        // Then
+        assert!(result.is_err());
+        assert_eq!(fs.generation, 1);
+        assert_eq!(contents(&mut fs, b"settings"), Some(b"new".to_vec()));
+        assert_eq!(names(&mut fs), vec![b"settings".to_vec()]);
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let mut flash = FakeFlash::uniform(8, BLOCK_SIZE);
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+
+        for i in 0..50u8 {
+            fs.write(b"counter", &[i; 400]).unwrap();
+        }
+
+        assert_eq!(contents(&mut fs, b"counter"), Some(vec![49; 400]));
+        assert!(flash.erase_cycles().iter().all(|cycles| *cycles > 0));
+    }
+
+    #[test]
+    fn invalid_names_and_oversized_files_are_rejected() {
+        let mut flash = FakeFlash::uniform(8, BLOCK_SIZE);
+        let mut fs = Filesystem::mount(&mut flash).unwrap();
+
+        assert_eq!(fs.write(b"", b"data").err(), Some(Error::EmptyName));
+        assert_eq!(fs.write(&[b'n'; MAX_NAME_SIZE + 1], b"").err(), Some(Error::NameTooLong));
+        assert_eq!(fs.delete(b"missing"), Err(Error::NotFound));
+        assert_eq!(fs.write(b"huge", &[0; 8 * BLOCK_SIZE]).err(), Some(Error::NoSpace));
+        let mut writer = fs.create(b"small", 4).unwrap();
+        assert_eq!(writer.write(&[0; 5]), Err(Error::FileTooLarge));
+    }
+}
@@ -0,0 +1,201 @@
+//! Firmware image header, so bootloaders can tell whether a bank holds a
+//! bootable image.
+//!
+//! The header sits at the start of the bank, immediately followed by the
+//! image itself. All fields are little endian:
+//!
+//! | magic | format | flags | version | size | image CRC32 | header CRC32 |
+//! |-------|--------|-------|---------|------|-------------|--------------|
+//! | u32   | u16    | u16   | u32     | u32  | u32         | u32          |
+use crate::hal::flash::{ReadWrite, CHUNK_SIZE};
+use crc::crc32;
+use nom::{
+    number::complete::{le_u16, le_u32},
+    sequence::tuple,
+    IResult,
+};
+
+pub const MAGIC: u32 = 0xB1FE_1A9E;
+pub const FORMAT_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 24;
+
+/// Header fields covered by the header CRC.
+const CHECKED_SIZE: usize = HEADER_SIZE - 4;
+const GOLDEN_FLAG: u16 = 0x0001;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Firmware version, as defined by the application.
+    pub version: u32,
+    /// Size of the image following the header, in bytes.
+    pub size: usize,
+    /// CRC32 (IEEE) of the image following the header.
+    pub crc: u32,
+    /// Marks a known good fallback image, which should never be overwritten.
+    pub golden: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    /// No image header (e.g. erased memory).
+    NotFound,
+    /// The header was written in a newer format.
+    UnsupportedFormat(u16),
+    /// The header fields don't match their checksum.
+    CorruptHeader,
+    /// The declared size exceeds the space available after the header.
+    TooLarge,
+    /// The declared size runs past the end of the flash device.
+    Truncated,
+    /// The image doesn't match its checksum.
+    CorruptImage,
+}
+
+impl Header {
+    /// Describes an image, for tooling producing bootable banks.
+    pub fn new(image: &[u8], version: u32, golden: bool) -> Self {
+        Self { version, size: image.len(), crc: crc32::checksum_ieee(image), golden }
+    }
+
+    pub fn parse<E>(bytes: &[u8]) -> Result<Self, Error<E>> {
+        let (_, (magic, format, flags, version, size, crc, header_crc)) =
+            fields(bytes).map_err(|_| Error::NotFound)?;
+        if magic != MAGIC {
+            return Err(Error::NotFound);
+        } else if format != FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat(format));
+        } else if crc32::checksum_ieee(&bytes[..CHECKED_SIZE]) != header_crc {
+            return Err(Error::CorruptHeader);
+        }
+        Ok(Self { version, size: size as usize, crc, golden: flags & GOLDEN_FLAG != 0 })
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        let flags = if self.golden { GOLDEN_FLAG } else { 0 };
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes[6..8].copy_from_slice(&flags.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&(self.size as u32).to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.crc.to_le_bytes());
+        let header_crc = crc32::checksum_ieee(&bytes[..CHECKED_SIZE]);
+        bytes[20..24].copy_from_slice(&header_crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// Magic, format, flags, version, size, image CRC and header CRC.
+type Fields = (u32, u16, u16, u32, u32, u32, u32);
+
+fn fields(input: &[u8]) -> IResult<&[u8], Fields> {
+    tuple((le_u32, le_u16, le_u16, le_u32, le_u32, le_u32, le_u32))(input)
+}
+
+/// Reads the header of the image in a bank, without checking the image.
+pub fn read_header<F: ReadWrite>(
+    flash: &mut F,
+    bank: F::Address,
+) -> Result<Header, Error<F::Error>> {
+    let mut bytes = [0u8; HEADER_SIZE];
+    nb::block!(flash.read(bank, &mut bytes)).map_err(Error::Flash)?;
+    Header::parse(&bytes)
+}
+
+/// Checks the header and contents of the image in a bank of a given size,
+/// returning the header if the image is bootable.
+pub fn validate<F: ReadWrite>(
+    flash: &mut F,
+    bank: F::Address,
+    bank_size: usize,
+) -> Result<Header, Error<F::Error>> {
+    let header = read_header(flash, bank)?;
+    if header.size > bank_size.saturating_sub(HEADER_SIZE) {
+        return Err(Error::TooLarge);
+    }
+
+    let image = bank + HEADER_SIZE;
+    if image + header.size > flash.range().1 {
+        return Err(Error::Truncated);
+    }
+
+    let mut crc = 0u32;
+    let mut buffer = [0u8; CHUNK_SIZE];
+    flash
+        .chunks(image, header.size, &mut buffer)
+        .try_for_each(|chunk| crc = crc32::update(crc, &crc32::IEEE_TABLE, chunk))
+        .map_err(Error::Flash)?;
+    if crc != header.crc {
+        Err(Error::CorruptImage)
+    } else {
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::doubles::flash::*;
+
+    const BANK: Address = Address(0x1000);
+    const BANK_SIZE: usize = 0x4000;
+
+    fn flash_with_image(image: &[u8], header: &Header) -> FakeFlash {
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(BANK, &header.encode()).unwrap();
+        flash.write(BANK + HEADER_SIZE, image).unwrap();
+        flash
+    }
+
+    #[test]
+    fn encoding_and_parsing_headers() {
+        let header = Header { version: 0x0102_0304, size: 1234, crc: 0xDEAD_BEEF, golden: true };
+
+        let bytes = header.encode();
+
+        assert_eq!(&bytes[..4], &MAGIC.to_le_bytes());
+        assert_eq!(Header::parse::<()>(&bytes), Ok(header));
+        assert_eq!(Header::parse::<()>(&bytes[..HEADER_SIZE - 1]), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn corrupted_or_unknown_headers_are_rejected() {
+        let mut bytes = Header::new(b"image", 1, false).encode();
+        bytes[8] ^= 0x01;
+        assert_eq!(Header::parse::<()>(&bytes), Err(Error::CorruptHeader));
+
+        bytes[4] = 2;
+        assert_eq!(Header::parse::<()>(&bytes), Err(Error::UnsupportedFormat(2)));
+        assert_eq!(Header::parse::<()>(&[0xFF; HEADER_SIZE]), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn validating_a_bootable_image() {
+        let image: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let header = Header::new(&image, 7, false);
+        let mut flash = flash_with_image(&image, &header);
+
+        assert_eq!(validate(&mut flash, BANK, BANK_SIZE), Ok(header));
+    }
+
+    #[test]
+    fn validating_corrupted_or_oversized_images() {
+        // Given
+        let image = [0x5A; 100];
+        let mut flash = flash_with_image(&image, &Header::new(&image, 1, false));
+        let oversized = Header { size: BANK_SIZE, ..Header::new(&image, 1, false) };
+        let mut oversized_flash = flash_with_image(&image, &oversized);
+
+        // When
+        flash.write(BANK + HEADER_SIZE + 50, &[0x00]).unwrap();
+
+        // Then
+        assert_eq!(validate(&mut flash, BANK, BANK_SIZE), Err(Error::CorruptImage));
+        assert_eq!(validate(&mut oversized_flash, BANK, BANK_SIZE), Err(Error::TooLarge));
+        assert_eq!(
+            validate(&mut FakeFlash::new(Address(0)), BANK, BANK_SIZE),
+            Err(Error::NotFound)
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! Log structured key-value store, generic over any flash with range erase.
+//!
+//! The store lives in two erase sectors. Records are only ever appended to
+//! the active sector, and a later record for a key supersedes any previous
+//! one. When the active sector runs out of space, the live records are
+//! compacted into the spare sector, which then becomes the active one, so
+//! wear is spread across both.
+//!
+//! Every record carries a CRC, and a sector only becomes active once its
+//! header is written after the compaction is complete, so an interrupted
+//! write or compaction never loses previously stored values.
+use crate::hal::flash::{self, EraseBlock};
+use crc::crc32;
+
+pub const MAX_KEY_SIZE: usize = 32;
+pub const MAX_VALUE_SIZE: usize = 256;
+
+/// Magic number followed by the sector generation, both little endian `u32`.
+const SECTOR_HEADER_SIZE: usize = 8;
+const SECTOR_MAGIC: u32 = 0x4B56_5354;
+
+/// Key size, flags, value size (little endian `u16`) and CRC32 of
+/// everything in the record but the CRC itself.
+const RECORD_HEADER_SIZE: usize = 8;
+const MAX_RECORD_SIZE: usize = RECORD_HEADER_SIZE + MAX_KEY_SIZE + MAX_VALUE_SIZE;
+
+/// Records are padded so they can be written on word aligned devices.
+const RECORD_ALIGNMENT: usize = 4;
+const TOMBSTONE: u8 = 0x01;
+const ERASED: u8 = 0xFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    EmptyKey,
+    KeyTooLong,
+    ValueTooLong,
+    /// The buffer passed to `get` can't hold the stored value.
+    BufferTooSmall {
+        required: usize,
+    },
+    /// Not enough space for the record even after compaction.
+    Full,
+}
+
+/// Result of reading the log at a given offset.
+enum Entry {
+    Valid(Record),
+    /// Nothing written past this point.
+    Erased,
+    /// Interrupted or corrupted write.
+    Corrupt,
+}
+
+#[derive(Copy, Clone)]
+struct Record {
+    key_size: usize,
+    value_size: usize,
+    flags: u8,
+}
+
+impl Record {
+    fn size(&self) -> usize {
+        let size = RECORD_HEADER_SIZE + self.key_size + self.value_size;
+        size.div_ceil(RECORD_ALIGNMENT) * RECORD_ALIGNMENT
+    }
+    fn key<'b>(&self, buffer: &'b [u8]) -> &'b [u8] {
+        &buffer[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + self.key_size]
+    }
+    fn value<'b>(&self, buffer: &'b [u8]) -> &'b [u8] {
+        let start = RECORD_HEADER_SIZE + self.key_size;
+        &buffer[start..start + self.value_size]
+    }
+    fn is_tombstone(&self) -> bool { self.flags & TOMBSTONE != 0 }
+}
+
+pub struct Store<'a, F: flash::EraseRegion> {
+    flash: &'a mut F,
+    sectors: [EraseBlock<F::Address>; 2],
+    active: usize,
+    generation: u32,
+    /// Offset of the first free byte in the active sector.
+    head: usize,
+}
+
+impl<'a, F: flash::EraseRegion> Store<'a, F> {
+    /// Opens the store held in two erase sectors, formatting it if neither
+    /// holds a valid one. Interrupted writes are cleaned up by compacting.
+    pub fn mount(
+        flash: &'a mut F,
+        sectors: [EraseBlock<F::Address>; 2],
+    ) -> Result<Self, Error<F::Error>> {
+        let mut store = Self { flash, sectors, active: 0, generation: 0, head: SECTOR_HEADER_SIZE };
+        let generations = [store.generation_of(0)?, store.generation_of(1)?];
+        let active = match generations {
+            [Some(a), Some(b)] => Some(if b > a { 1 } else { 0 }),
+            [Some(_), None] => Some(0),
+            [None, Some(_)] => Some(1),
+            [None, None] => None,
+        };
+
+        match active {
+            None => store.format(0, 1)?,
+            Some(active) => {
+                store.active = active;
+                store.generation = generations[active].unwrap_or_default();
+                if !store.find_head()? {
+                    store.compact()?;
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    /// Copies the value stored for a key into the buffer, returning its size,
+    /// or `None` if the key isn't stored.
+    pub fn get(&mut self, key: &[u8], value: &mut [u8]) -> Result<Option<usize>, Error<F::Error>> {
+        let mut buffer = [0u8; MAX_RECORD_SIZE];
+        let record = match self.find(self.active, key, &mut buffer)? {
+            Some(record) if !record.is_tombstone() => record,
+            _ => return Ok(None),
+        };
+        if value.len() < record.value_size {
+            return Err(Error::BufferTooSmall { required: record.value_size });
+        }
+        value[..record.value_size].copy_from_slice(record.value(&buffer));
+        Ok(Some(record.value_size))
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error<F::Error>> {
+        self.append(key, value, 0)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<(), Error<F::Error>> {
+        let mut buffer = [0u8; MAX_RECORD_SIZE];
+        match self.find(self.active, key, &mut buffer)? {
+            Some(record) if !record.is_tombstone() => self.append(key, &[], TOMBSTONE),
+            _ => Ok(()),
+        }
+    }
+
+    fn append(&mut self, key: &[u8], value: &[u8], flags: u8) -> Result<(), Error<F::Error>> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        } else if key.len() > MAX_KEY_SIZE {
+            return Err(Error::KeyTooLong);
+        } else if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::ValueTooLong);
+        }
+
+        let record = Record { key_size: key.len(), value_size: value.len(), flags };
+        if self.head + record.size() > self.sectors[self.active].size {
+            self.compact()?;
+            if self.head + record.size() > self.sectors[self.active].size {
+                return Err(Error::Full);
+            }
+        }
+
+        let mut buffer = [ERASED; MAX_RECORD_SIZE];
+        buffer[0] = key.len() as u8;
+        buffer[1] = flags;
+        buffer[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        buffer[RECORD_HEADER_SIZE..][..key.len()].copy_from_slice(key);
+        buffer[RECORD_HEADER_SIZE + key.len()..][..value.len()].copy_from_slice(value);
+        let crc = record_crc(&record, &buffer);
+        buffer[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        let address = self.sectors[self.active].start + self.head;
+        nb::block!(self.flash.write(address, &buffer[..record.size()])).map_err(Error::Flash)?;
+        self.head += record.size();
+        Ok(())
+    }
+
+    /// Latest record for a key in a sector.
+    fn find(
+        &mut self,
+        sector: usize,
+        key: &[u8],
+        buffer: &mut [u8; MAX_RECORD_SIZE],
+    ) -> Result<Option<Record>, Error<F::Error>> {
+        self.find_from(sector, SECTOR_HEADER_SIZE, key, buffer)
+    }
+
+    fn find_from(
+        &mut self,
+        sector: usize,
+        mut offset: usize,
+        key: &[u8],
+        buffer: &mut [u8; MAX_RECORD_SIZE],
+    ) -> Result<Option<Record>, Error<F::Error>> {
+        let mut candidate = [0u8; MAX_RECORD_SIZE];
+        let mut found = None;
+        while let Entry::Valid(record) = self.read_entry(sector, offset, &mut candidate)? {
+            if record.key(&candidate) == key {
+                buffer.copy_from_slice(&candidate);
+                found = Some(record);
+            }
+            offset += record.size();
+        }
+        Ok(found)
+    }
+
+    fn read_entry(
+        &mut self,
+        sector: usize,
+        offset: usize,
+        buffer: &mut [u8; MAX_RECORD_SIZE],
+    ) -> Result<Entry, Error<F::Error>> {
+        let EraseBlock { start, size } = self.sectors[sector];
+        if offset + RECORD_HEADER_SIZE > size {
+            return Ok(Entry::Erased);
+        }
+        nb::block!(self.flash.read(start + offset, &mut buffer[..RECORD_HEADER_SIZE]))
+            .map_err(Error::Flash)?;
+        if buffer[0] == ERASED {
+            return Ok(Entry::Erased);
+        }
+
+        let value_size = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+        let record = Record { key_size: buffer[0] as usize, value_size, flags: buffer[1] };
+        let valid_sizes = (1..=MAX_KEY_SIZE).contains(&record.key_size)
+            && (record.value_size <= MAX_VALUE_SIZE)
+            && (offset + record.size() <= size);
+        if !valid_sizes {
+            return Ok(Entry::Corrupt);
+        }
+
+        let body = &mut buffer[RECORD_HEADER_SIZE..][..record.key_size + record.value_size];
+        nb::block!(self.flash.read(start + offset + RECORD_HEADER_SIZE, body))
+            .map_err(Error::Flash)?;
+        let crc = u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+        Ok(if record_crc(&record, buffer) == crc { Entry::Valid(record) } else { Entry::Corrupt })
+    }
+
+    /// Moves the head past the last record of the active sector, returning
+    /// whether the rest of the sector is cleanly erased.
+    fn find_head(&mut self) -> Result<bool, Error<F::Error>> {
+        let mut buffer = [0u8; MAX_RECORD_SIZE];
+        let mut offset = SECTOR_HEADER_SIZE;
+        loop {
+            match self.read_entry(self.active, offset, &mut buffer)? {
+                Entry::Valid(record) => offset += record.size(),
+                Entry::Corrupt => return Ok(false),
+                Entry::Erased => break,
+            }
+        }
+        self.head = offset;
+
+        let EraseBlock { start, size } = self.sectors[self.active];
+        let mut chunk = [0u8; MAX_RECORD_SIZE];
+        while offset < size {
+            let chunk = &mut chunk[..MAX_RECORD_SIZE.min(size - offset)];
+            nb::block!(self.flash.read(start + offset, chunk)).map_err(Error::Flash)?;
+            if chunk.iter().any(|b| *b != ERASED) {
+                return Ok(false);
+            }
+            offset += chunk.len();
+        }
+        Ok(true)
+    }
+
+    fn generation_of(&mut self, sector: usize) -> Result<Option<u32>, Error<F::Error>> {
+        let mut header = [0u8; SECTOR_HEADER_SIZE];
+        nb::block!(self.flash.read(self.sectors[sector].start, &mut header))
+            .map_err(Error::Flash)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let generation = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Ok((magic == SECTOR_MAGIC).then_some(generation))
+    }
+
+    /// Copies every live record into the spare sector, then makes it active.
+    fn compact(&mut self) -> Result<(), Error<F::Error>> {
+        let (source, target) = (self.active, 1 - self.active);
+        self.erase(target)?;
+
+        let mut buffer = [0u8; MAX_RECORD_SIZE];
+        let mut latest = [0u8; MAX_RECORD_SIZE];
+        let (mut offset, mut head) = (SECTOR_HEADER_SIZE, SECTOR_HEADER_SIZE);
+        while let Entry::Valid(record) = self.read_entry(source, offset, &mut buffer)? {
+            let next = offset + record.size();
+            let superseded =
+                self.find_from(source, next, record.key(&buffer), &mut latest)?.is_some();
+            if !superseded && !record.is_tombstone() {
+                let address = self.sectors[target].start + head;
+                nb::block!(self.flash.write(address, &buffer[..record.size()]))
+                    .map_err(Error::Flash)?;
+                head += record.size();
+            }
+            offset = next;
+        }
+
+        self.activate(target, self.generation.wrapping_add(1))?;
+        self.head = head;
+        Ok(())
+    }
+
+    fn format(&mut self, sector: usize, generation: u32) -> Result<(), Error<F::Error>> {
+        self.erase(sector)?;
+        self.activate(sector, generation)?;
+        self.head = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    fn activate(&mut self, sector: usize, generation: u32) -> Result<(), Error<F::Error>> {
+        let mut header = [0u8; SECTOR_HEADER_SIZE];
+        header[..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&generation.to_le_bytes());
+        nb::block!(self.flash.write(self.sectors[sector].start, &header)).map_err(Error::Flash)?;
+        self.active = sector;
+        self.generation = generation;
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Error<F::Error>> {
+        let sector = self.sectors[sector];
+        nb::block!(self.flash.erase_range(sector.start, sector.end())).map_err(Error::Flash)
+    }
+}
+
+fn record_crc(record: &Record, buffer: &[u8]) -> u32 {
+    let body =
+        &buffer[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + record.key_size + record.value_size];
+    crc32::update(crc32::checksum_ieee(&buffer[..4]), &crc32::IEEE_TABLE, body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::{
+        doubles::{
+            faulty_flash::{Fault, FaultyFlash, Tear},
+            flash::FakeFlash,
+        },
+        flash::Geometry,
+    };
+
+    const SECTOR_SIZE: usize = 256;
+
+    fn sectors<F: Geometry>(flash: &F) -> [EraseBlock<F::Address>; 2] {
+        [flash.erase_block(1).unwrap(), flash.erase_block(2).unwrap()]
+    }
+
+    fn get<F: flash::EraseRegion>(store: &mut Store<F>, key: &[u8]) -> Option<Vec<u8>> {
+        let mut value = [0u8; MAX_VALUE_SIZE];
+        let size = store.get(key, &mut value).map_err(|_| ()).unwrap()?;
+        Some(value[..size].to_vec())
+    }
+
+    #[test]
+    fn storing_updating_and_removing_values() {
+        // Given
+        let mut flash = FakeFlash::uniform(3, SECTOR_SIZE);
+        let sectors = sectors(&flash);
+        let mut store = Store::mount(&mut flash, sectors).unwrap();
+
+        // When
+        store.set(b"serial", b"ABC-123").unwrap();
+        store.set(b"calibration", &[1, 2, 3]).unwrap();
+        store.set(b"serial", b"XYZ-789").unwrap();
+        store.remove(b"calibration").unwrap();
+
+        // Then
+        assert_eq!(get(&mut store, b"serial"), Some(b"XYZ-789".to_vec()));
+        assert_eq!(get(&mut store, b"calibration"), None);
+        assert_eq!(get(&mut store, b"missing"), None);
+        let mut small = [0u8; 2];
+        assert_eq!(store.get(b"serial", &mut small), Err(Error::BufferTooSmall { required: 7 }));
+    }
+
+    #[test]
+    fn values_persist_across_mounts() {
+        let mut flash = FakeFlash::uniform(3, SECTOR_SIZE);
+        let sectors = sectors(&flash);
+        Store::mount(&mut flash, sectors).unwrap().set(b"network", b"10.0.0.1").unwrap();
+
+        let mut store = Store::mount(&mut flash, sectors).unwrap();
+
+        assert_eq!(get(&mut store, b"network"), Some(b"10.0.0.1".to_vec()));
+    }
+
+    #[test]
+    fn full_sectors_are_compacted_into_the_spare_one() {
+        // Given
+        let mut flash = FakeFlash::uniform(3, SECTOR_SIZE);
+        let sectors = sectors(&flash);
+        let mut store = Store::mount(&mut flash, sectors).unwrap();
+        store.set(b"constant", b"kept").unwrap();
+
+        // When
+        for i in 0..100u8 {
+            store.set(b"counter", &[i]).unwrap();
+        }
+
+        // Then
+        assert_eq!(get(&mut store, b"constant"), Some(b"kept".to_vec()));
+        assert_eq!(get(&mut store, b"counter"), Some(vec![99]));
+        let cycles = flash.erase_cycles();
+        assert_eq!(cycles[0], 0);
+        assert!(cycles[1] > 1 && cycles[2] > 1);
+    }
+
+    #[test]
+    fn oversized_records_are_rejected() {
+        let mut flash = FakeFlash::uniform(3, SECTOR_SIZE);
+        let sectors = sectors(&flash);
+        let mut store = Store::mount(&mut flash, sectors).unwrap();
+
+        assert_eq!(store.set(b"", b"value"), Err(Error::EmptyKey));
+        assert_eq!(store.set(&[b'k'; MAX_KEY_SIZE + 1], b"value"), Err(Error::KeyTooLong));
+        assert_eq!(store.set(b"key", &[0; MAX_VALUE_SIZE + 1]), Err(Error::ValueTooLong));
+        store.set(b"a", &[0; 200]).unwrap();
+        assert_eq!(store.set(b"b", &[0; 200]), Err(Error::Full));
+        assert_eq!(get(&mut store, b"a"), Some(vec![0; 200]));
+    }
+
+    #[test]
+    fn interrupted_writes_keep_previous_values() {
+        // Given
+        let mut flash = FaultyFlash::new(FakeFlash::uniform(3, SECTOR_SIZE));
+        let sectors = sectors(&flash);
+        Store::mount(&mut flash, sectors).unwrap().set(b"mode", b"safe").unwrap();
+        let tear = Tear::Randomised { seed: 3 };
+        flash.inject(Fault::PowerLossOnWrite { write: 0, programmed: 6, tear });
+
+        // When
+        assert!(Store::mount(&mut flash, sectors).unwrap().set(b"mode", b"fast").is_err());
+        flash.restore_power();
+        let mut store = Store::mount(&mut flash, sectors).unwrap();
+
+        // Then
+        assert_eq!(get(&mut store, b"mode"), Some(b"safe".to_vec()));
+        store.set(b"mode", b"fast").unwrap();
+        assert_eq!(get(&mut store, b"mode"), Some(b"fast".to_vec()));
+    }
+
+    #[test]
+    fn interrupted_compactions_keep_previous_values() {
+        // Given
+        let mut flash = FaultyFlash::new(FakeFlash::uniform(3, SECTOR_SIZE));
+        let sectors = sectors(&flash);
+        let mut store = Store::mount(&mut flash, sectors).unwrap();
+        store.set(b"constant", b"kept").unwrap();
+        while store.head + 16 <= SECTOR_SIZE {
+            store.set(b"counter", &[1]).unwrap();
+        }
+
+        for write in 0..3 {
+            let tear = Tear::Randomised { seed: 11 };
+            flash.inject(Fault::PowerLossOnWrite { write, programmed: 2, tear });
+
+            // When
+            let mut store = Store::mount(&mut flash, sectors).unwrap();
+            assert!(store.set(b"counter", &[2]).is_err());
+            flash.restore_power();
+
+            // Then
+            let mut store = Store::mount(&mut flash, sectors).unwrap();
+            assert_eq!(get(&mut store, b"constant"), Some(b"kept".to_vec()));
+            assert_eq!(get(&mut store, b"counter"), Some(vec![1]));
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Windowed view over a flash device, so each subsystem only sees (and can
+//! only modify) its own region.
+//!
+//! Addresses within a partition are offsets from its start, and every access
+//! outside of it is refused. Partitions cover whole erase blocks, so erasing
+//! a partition can never affect its neighbours.
+use crate::hal::flash::{EraseBlock, EraseRegion, Geometry, ReadWrite};
+use core::ops::{Add, Sub};
+
+/// Address relative to the start of a partition.
+#[derive(Default, Copy, Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
+pub struct Offset(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    /// The access falls (at least partly) outside the partition.
+    OutOfBounds,
+}
+
+pub struct Partition<F: EraseRegion + Geometry> {
+    inner: F,
+    start: F::Address,
+    size: usize,
+    /// Index and number of the device erase blocks spanned by the partition.
+    blocks: (usize, usize),
+}
+
+impl<F: EraseRegion + Geometry> Partition<F> {
+    /// Partitions `size` bytes of a device from an address onwards. Returns
+    /// `None` unless the window lies within the device and starts and ends
+    /// at erase block boundaries.
+    pub fn new(inner: F, start: F::Address, size: usize) -> Option<Self> {
+        let end = start + size;
+        let first = inner.erase_blocks().position(|block| block.start == start)?;
+        let count = inner.erase_blocks().skip(first).position(|block| block.end() == end)? + 1;
+        Some(Self { inner, start, size, blocks: (first, count) })
+    }
+
+    /// Start of the partition, in device addresses.
+    pub fn start(&self) -> F::Address { self.start }
+
+    pub fn size(&self) -> usize { self.size }
+
+    pub fn into_inner(self) -> F { self.inner }
+
+    /// Device address of an access within the partition, if it fits.
+    fn translate(&self, offset: Offset, size: usize) -> Result<F::Address, Error<F::Error>> {
+        match offset.0.checked_add(size) {
+            Some(end) if end <= self.size => Ok(self.start + offset.0),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+}
+
+impl<F: EraseRegion + Geometry> ReadWrite for Partition<F> {
+    type Error = Error<F::Error>;
+    type Address = Offset;
+
+    fn label() -> &'static str { F::label() }
+
+    fn read(&mut self, address: Offset, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        let address = self.translate(address, bytes.len())?;
+        self.inner.read(address, bytes).map_err(|e| e.map(Error::Flash))
+    }
+
+    fn write(&mut self, address: Offset, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        let address = self.translate(address, bytes.len())?;
+        self.inner.write(address, bytes).map_err(|e| e.map(Error::Flash))
+    }
+
+    fn range(&self) -> (Offset, Offset) { (Offset(0), Offset(self.size)) }
+
+    /// Erases the partition only.
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        self.inner.erase_range(self.start, self.start + self.size).map_err(|e| e.map(Error::Flash))
+    }
+
+    /// Blocks that would overflow the partition aren't written, and the
+    /// write fails once the partition is full.
+    fn write_from_blocks<I: Iterator<Item = [u8; N]>, const N: usize>(
+        &mut self,
+        address: Offset,
+        blocks: I,
+    ) -> Result<(), Self::Error> {
+        let start = self.translate(address, 0)?;
+        let capacity = (self.size - address.0) / N;
+        let mut blocks = blocks.peekable();
+        self.inner
+            .write_from_blocks(start, blocks.by_ref().take(capacity))
+            .map_err(Error::Flash)?;
+        match blocks.peek() {
+            Some(_) => Err(Error::OutOfBounds),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<F: EraseRegion + Geometry> EraseRegion for Partition<F> {
+    fn erase_range(&mut self, start: Offset, end: Offset) -> nb::Result<(), Self::Error> {
+        if start > end {
+            return Err(nb::Error::Other(Error::OutOfBounds));
+        }
+        let device_start = self.translate(start, end.0 - start.0)?;
+        let device_end = device_start + (end.0 - start.0);
+        self.inner.erase_range(device_start, device_end).map_err(|e| e.map(Error::Flash))
+    }
+}
+
+impl<F: EraseRegion + Geometry> Geometry for Partition<F> {
+    fn erase_block_count(&self) -> usize { self.blocks.1 }
+
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Offset>> {
+        if index >= self.blocks.1 {
+            return None;
+        }
+        let block = self.inner.erase_block(self.blocks.0 + index)?;
+        Some(EraseBlock { start: Offset(block.start - self.start), size: block.size })
+    }
+
+    fn write_alignment(&self) -> usize { self.inner.write_alignment() }
+    fn erased_value(&self) -> u8 { self.inner.erased_value() }
+}
+
+impl Add<usize> for Offset {
+    type Output = Self;
+    fn add(self, rhs: usize) -> Offset { Offset(self.0 + rhs) }
+}
+
+impl Sub<usize> for Offset {
+    type Output = Self;
+    fn sub(self, rhs: usize) -> Offset { Offset(self.0.saturating_sub(rhs)) }
+}
+
+impl Sub<Offset> for Offset {
+    type Output = usize;
+    fn sub(self, rhs: Offset) -> usize { self.0.saturating_sub(rhs.0) }
+}
+
+impl From<Offset> for usize {
+    fn from(offset: Offset) -> Self { offset.0 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::doubles::flash::*;
+
+    const LAYOUT: &[(usize, usize)] = &[(2, 256), (2, 1024)];
+
+    fn fake_flash() -> FakeFlash {
+        FakeFlash::with_layout(Address(0x1000), LAYOUT, ProgramMode::Strict)
+    }
+
+    #[test]
+    fn partitions_must_cover_whole_erase_blocks() {
+        let start = Address(0x1000);
+
+        assert!(Partition::new(fake_flash(), start, 512).is_some());
+        assert!(Partition::new(fake_flash(), start + 256, 1024 + 256).is_some());
+        assert!(Partition::new(fake_flash(), start + 1, 255).is_none());
+        assert!(Partition::new(fake_flash(), start, 300).is_none());
+        assert!(Partition::new(fake_flash(), start + 512, 4096).is_none());
+    }
+
+    #[test]
+    fn partition_accesses_are_relative_and_bounded() {
+        // Given
+        let mut flash = fake_flash();
+        let mut partition = Partition::new(&mut flash, Address(0x1100), 1280).unwrap();
+
+        // When
+        partition.write(Offset(10), &[0xAA, 0xBB]).unwrap();
+        let outside = partition.write(Offset(1279), &[0x00, 0x00]);
+
+        // Then
+        assert_eq!(outside, Err(nb::Error::Other(Error::OutOfBounds)));
+        assert_eq!(partition.range(), (Offset(0), Offset(1280)));
+        let sizes: Vec<_> = partition.erase_blocks().map(|b| (b.start, b.size)).collect();
+        assert_eq!(sizes, vec![(Offset(0), 256), (Offset(256), 1024)]);
+        assert_eq!(contents(&mut flash, Address(0x110A), 2), [0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn erasing_a_partition_leaves_its_neighbours_untouched() {
+        // Given
+        let mut flash = fake_flash();
+        flash.write(Address(0x1000), &[0x00; 2560]).unwrap();
+        let mut partition = Partition::new(&mut flash, Address(0x1100), 1280).unwrap();
+
+        // When
+        partition.erase().unwrap();
+
+        // Then
+        assert_eq!(flash.erase_cycles(), &[0, 1, 1, 0]);
+        assert_eq!(contents(&mut flash, Address(0x10FF), 2), [0x00, 0xFF]);
+    }
+
+    #[test]
+    fn block_writes_stop_at_the_end_of_the_partition() {
+        let mut flash = fake_flash();
+        let mut partition = Partition::new(&mut flash, Address(0x1000), 256).unwrap();
+
+        let result = partition.write_from_blocks(Offset(248), core::iter::repeat_n([0u8; 4], 3));
+
+        assert_eq!(result, Err(Error::OutOfBounds));
+        assert_eq!(contents(&mut flash, Address(0x1100), 1), [0xFF]);
+    }
+}
@@ -0,0 +1,132 @@
+//! Image digests and signature verification for secure boot.
+//!
+//! Images are hashed with SHA-256 straight from flash, through a small stack
+//! buffer, and signed with ECDSA over the P-256 curve. The signature is
+//! appended to the image as the raw 64 byte `r || s` pair.
+use crate::hal::flash::{ReadWrite, CHUNK_SIZE};
+use p256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+pub const DIGEST_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// The image was signed by the owner of the public key.
+    Verified,
+    /// The image or its signature was altered, or signed with a different key.
+    Unverified,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    /// The public key isn't a valid SEC1 encoded P-256 point.
+    InvalidKey,
+}
+
+/// SHA-256 digest of `size` bytes of flash, starting at an address.
+pub fn sha256<F: ReadWrite>(
+    flash: &mut F,
+    start: F::Address,
+    size: usize,
+) -> Result<[u8; DIGEST_SIZE], F::Error> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; CHUNK_SIZE];
+    flash.chunks(start, size, &mut buffer).try_for_each(|chunk| hasher.update(chunk))?;
+    Ok(hasher.finalize().into())
+}
+
+/// Verifies the signature appended to an image of `size` bytes against a
+/// SEC1 encoded (compressed or uncompressed) public key, typically compiled
+/// into the bootloader.
+pub fn verify<F: ReadWrite>(
+    flash: &mut F,
+    start: F::Address,
+    size: usize,
+    public_key: &[u8],
+) -> Result<Verification, Error<F::Error>> {
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| Error::InvalidKey)?;
+    let digest = sha256(flash, start, size).map_err(Error::Flash)?;
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    nb::block!(flash.read(start + size, &mut signature)).map_err(Error::Flash)?;
+
+    let verified = Signature::from_slice(&signature)
+        .and_then(|signature| key.verify_prehash(&digest, &signature))
+        .is_ok();
+    Ok(if verified { Verification::Verified } else { Verification::Unverified })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::doubles::flash::*;
+    use p256::ecdsa::{signature::hazmat::PrehashSigner, SigningKey};
+
+    const IMAGE_START: Address = Address(0x100);
+
+    fn hex(string: &str) -> Vec<u8> {
+        (0..string.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&string[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn digest_of(bytes: &[u8]) -> [u8; DIGEST_SIZE] {
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(IMAGE_START, bytes).unwrap();
+        sha256(&mut flash, IMAGE_START, bytes.len()).unwrap()
+    }
+
+    fn signed_image(image: &[u8]) -> (FakeFlash, Vec<u8>) {
+        let key = SigningKey::from_slice(&[0x2A; 32]).unwrap();
+        let digest: [u8; DIGEST_SIZE] = Sha256::digest(image).into();
+        let signature: Signature = key.sign_prehash(&digest).unwrap();
+
+        let mut flash = FakeFlash::new(Address(0));
+        flash.write(IMAGE_START, image).unwrap();
+        flash.write(IMAGE_START + image.len(), &signature.to_bytes()).unwrap();
+        let public_key = key.verifying_key().to_encoded_point(false).as_bytes().to_vec();
+        (flash, public_key)
+    }
+
+    #[test]
+    fn hashing_flash_contents_matches_known_vectors() {
+        let empty = hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        let abc = hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let long = hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+
+        assert_eq!(&digest_of(b"")[..], &empty[..]);
+        assert_eq!(&digest_of(b"abc")[..], &abc[..]);
+        assert_eq!(&digest_of(&[b'a'; 1_000_000])[..], &long[..]);
+    }
+
+    #[test]
+    fn verifying_signed_images() {
+        let image: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+        let (mut flash, public_key) = signed_image(&image);
+
+        let result = verify(&mut flash, IMAGE_START, image.len(), &public_key);
+
+        assert_eq!(result, Ok(Verification::Verified));
+    }
+
+    #[test]
+    fn tampered_images_or_foreign_keys_are_unverified() {
+        // Given
+        let image = [0x33u8; 500];
+        let (mut flash, public_key) = signed_image(&image);
+        let foreign_key = SigningKey::from_slice(&[0x07; 32]).unwrap();
+        let foreign_key = foreign_key.verifying_key().to_encoded_point(true);
+
+        // When
+        let foreign = verify(&mut flash, IMAGE_START, image.len(), foreign_key.as_bytes());
+        flash.write(IMAGE_START + 10, &[0x00]).unwrap();
+        let tampered = verify(&mut flash, IMAGE_START, image.len(), &public_key);
+
+        // Then
+        assert_eq!(foreign, Ok(Verification::Unverified));
+        assert_eq!(tampered, Ok(Verification::Unverified));
+        assert_eq!(verify(&mut flash, IMAGE_START, 0, &[0x04; 3]), Err(Error::InvalidKey));
+    }
+}
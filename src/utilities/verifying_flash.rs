@@ -0,0 +1,161 @@
+//! Read-back verification for any flash.
+//!
+//! Every write and erase performed through the wrapper is read back and
+//! compared with the intended contents, so marginal cells or driver bugs
+//! surface as errors instead of silently corrupting data.
+use crate::hal::flash::{EraseBlock, EraseRegion, Geometry, ReadWrite, CHUNK_SIZE};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E, A> {
+    Flash(E),
+    /// Memory read back differently from what was written or erased, first
+    /// at this address.
+    VerifyFailed {
+        address: A,
+    },
+}
+
+pub struct VerifyingFlash<F: ReadWrite> {
+    pub inner: F,
+    erased_value: u8,
+}
+
+impl<F: ReadWrite> VerifyingFlash<F> {
+    /// Verifies erases against the usual NOR erased value, 0xFF.
+    pub fn new(inner: F) -> Self { Self::with_erased_value(inner, 0xFF) }
+
+    pub fn with_erased_value(inner: F, erased_value: u8) -> Self { Self { inner, erased_value } }
+
+    pub fn into_inner(self) -> F { self.inner }
+
+    /// Compares `size` bytes of memory from an address onwards with the
+    /// expected value at each offset.
+    fn verify<X: Fn(usize) -> u8>(
+        &mut self,
+        address: F::Address,
+        size: usize,
+        expected: X,
+    ) -> Result<(), Error<F::Error, F::Address>> {
+        let mut buffer = [0u8; CHUNK_SIZE];
+        let mut chunks = self.inner.chunks(address, size, &mut buffer);
+        let mut offset = 0;
+        while let Some(chunk) = chunks.next_chunk() {
+            let chunk = chunk.map_err(Error::Flash)?;
+            if let Some(index) = chunk.iter().zip(offset..).position(|(b, i)| *b != expected(i)) {
+                return Err(Error::VerifyFailed { address: address + offset + index });
+            }
+            offset += chunk.len();
+        }
+        Ok(())
+    }
+}
+
+impl<F: ReadWrite> ReadWrite for VerifyingFlash<F> {
+    type Error = Error<F::Error, F::Address>;
+    type Address = F::Address;
+
+    fn label() -> &'static str { F::label() }
+
+    fn read(&mut self, address: Self::Address, bytes: &mut [u8]) -> nb::Result<(), Self::Error> {
+        self.inner.read(address, bytes).map_err(|e| e.map(Error::Flash))
+    }
+
+    fn write(&mut self, address: Self::Address, bytes: &[u8]) -> nb::Result<(), Self::Error> {
+        self.inner.write(address, bytes).map_err(|e| e.map(Error::Flash))?;
+        Ok(self.verify(address, bytes.len(), |offset| bytes[offset])?)
+    }
+
+    fn range(&self) -> (Self::Address, Self::Address) { self.inner.range() }
+
+    fn erase(&mut self) -> nb::Result<(), Self::Error> {
+        self.inner.erase().map_err(|e| e.map(Error::Flash))?;
+        let (start, end) = self.inner.range();
+        let erased_value = self.erased_value;
+        Ok(self.verify(start, end - start, |_| erased_value)?)
+    }
+}
+
+impl<F: EraseRegion> EraseRegion for VerifyingFlash<F> {
+    fn erase_range(
+        &mut self,
+        start: Self::Address,
+        end: Self::Address,
+    ) -> nb::Result<(), Self::Error> {
+        self.inner.erase_range(start, end).map_err(|e| e.map(Error::Flash))?;
+        let erased_value = self.erased_value;
+        Ok(self.verify(start, end - start, |_| erased_value)?)
+    }
+}
+
+impl<F: Geometry> Geometry for VerifyingFlash<F> {
+    fn erase_block_count(&self) -> usize { self.inner.erase_block_count() }
+    fn erase_block(&self, index: usize) -> Option<EraseBlock<Self::Address>> {
+        self.inner.erase_block(index)
+    }
+    fn write_alignment(&self) -> usize { self.inner.write_alignment() }
+    fn erased_value(&self) -> u8 { self.inner.erased_value() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::doubles::flash::*;
+
+    fn verifying_flash(mode: ProgramMode) -> VerifyingFlash<FakeFlash> {
+        VerifyingFlash::new(FakeFlash::with_layout(Address(0), &[(4, 1024)], mode))
+    }
+
+    #[test]
+    fn verified_writes_and_erases_succeed() {
+        // Given
+        let mut flash = verifying_flash(ProgramMode::Strict);
+        let data: Vec<u8> = (0..1500).map(|i| (i % 256) as u8).collect();
+
+        // When
+        flash.write(Address(100), &data).unwrap();
+        flash.erase_range(Address(0), Address(1024)).unwrap();
+        flash.write_from_blocks(Address(2048), core::iter::repeat_n([0x12u8; 4], 8)).unwrap();
+
+        // Then
+        let mut bytes = [0u8; 4];
+        flash.read(Address(1024), &mut bytes).unwrap();
+        assert_eq!(bytes, [data[924], data[925], data[926], data[927]]);
+        flash.read(Address(2048 + 28), &mut bytes).unwrap();
+        assert_eq!(bytes, [0x12; 4]);
+        flash.erase().unwrap();
+    }
+
+    #[test]
+    fn writes_that_dont_read_back_are_reported() {
+        // Given
+        let mut flash = verifying_flash(ProgramMode::BitwiseAnd);
+        flash.write(Address(10), &[0xF0, 0xF0, 0xF0]).unwrap();
+
+        // When
+        let result = flash.write(Address(9), &[0xFF, 0xF0, 0x0F, 0xF0]);
+
+        // Then
+        assert_eq!(result, Err(nb::Error::Other(Error::VerifyFailed { address: Address(11) })));
+    }
+
+    #[test]
+    fn erases_that_dont_read_back_are_reported() {
+        let fake = FakeFlash::with_layout(Address(0), &[(4, 1024)], ProgramMode::Strict);
+        let mut flash = VerifyingFlash::with_erased_value(fake, 0x00);
+
+        let result = flash.erase_range(Address(1030), Address(1040));
+
+        assert_eq!(result, Err(nb::Error::Other(Error::VerifyFailed { address: Address(1030) })));
+    }
+
+    #[test]
+    fn flash_errors_are_forwarded() {
+        let mut flash = verifying_flash(ProgramMode::Strict);
+        flash.write(Address(0), &[0x00]).unwrap();
+
+        assert_eq!(
+            flash.write(Address(0), &[0x01]),
+            Err(nb::Error::Other(Error::Flash(FakeFlashError::ProgramWithoutErase(Address(0)))))
+        );
+    }
+}
@@ -1,4 +1,9 @@
 //! Xmodem protocol parser.
+//!
+//! Understands the original 128 byte packets with an additive checksum, as
+//! well as the XMODEM-CRC (CRC-16 trailer) and XMODEM-1K (1024 byte payload)
+//! extensions. The error detection scheme is negotiated by the receiver at the
+//! start of a session, so it must be supplied to the parser.
 
 use core::convert::TryInto;
 use nom::{
@@ -10,43 +15,146 @@ use nom::{
 
 use crate::hal::time::Seconds;
 
+mod receiver;
+mod sender;
+pub(crate) use self::receiver::{Flow, Session};
+pub use self::{
+    receiver::{Error as ReceiveError, Receiver, Transfer, MAX_RETRIES},
+    sender::{BlockSize, Error as SendError, Sender},
+};
+
 pub const PAYLOAD_SIZE: usize = 128;
+pub const LARGE_PAYLOAD_SIZE: usize = 1024;
+/// Largest packet with a standard payload and an additive checksum.
 pub const MAX_PACKET_SIZE: usize = 132;
+/// Largest possible packet (XMODEM-1K with a CRC-16 trailer).
+pub const MAX_LARGE_PACKET_SIZE: usize = 3 + LARGE_PAYLOAD_SIZE + 2;
 pub const DEFAULT_TIMEOUT: Seconds = Seconds(3);
 
 pub const ACK: u8 = 0x06;
 pub const NAK: u8 = 0x15;
 pub const SOH: u8 = 0x01;
+pub const STX: u8 = 0x02;
 pub const EOT: u8 = 0x04;
 pub const ETB: u8 = 0x17;
 pub const CAN: u8 = 0x18;
+/// Pads the last block of a transfer.
+pub const SUB: u8 = 0x1A;
+/// Sent by the receiver instead of a `NAK` to request CRC-16 packets.
+pub const CRC_REQUEST: u8 = b'C';
+
+/// Error detection scheme trailing every packet.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Checksum {
+    /// Original 8-bit additive checksum.
+    Additive,
+    /// CRC-16/XMODEM, big endian.
+    Crc16,
+}
+
+impl Checksum {
+    /// Byte sent by the receiver to start a session using this scheme.
+    pub fn request(self) -> u8 {
+        match self {
+            Checksum::Additive => NAK,
+            Checksum::Crc16 => CRC_REQUEST,
+        }
+    }
+
+    /// Size of the packet trailer in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            Checksum::Additive => 1,
+            Checksum::Crc16 => 2,
+        }
+    }
+}
 
 #[derive(Debug, Eq, PartialEq)]
-pub struct Chunk {
+pub struct Chunk<const N: usize = PAYLOAD_SIZE> {
     pub block_number: u8,
-    pub payload: [u8; PAYLOAD_SIZE],
+    pub payload: [u8; N],
 }
 
+/// XMODEM-1K chunk, started by `STX` rather than `SOH`.
+pub type LargeChunk = Chunk<LARGE_PAYLOAD_SIZE>;
+
+// No heap to box the large variant into; messages are short lived on the stack.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Eq, PartialEq)]
 pub enum Message {
     Chunk(Chunk),
+    LargeChunk(LargeChunk),
     EndOfTransmission,
     EndOfTransmissionBlock,
     Cancel,
 }
 
+/// Parses a message from a session using the original additive checksum.
 pub fn parse_message(input: &[u8]) -> IResult<&[u8], Message> {
-    alt((parse_chunk, parse_eot, parse_etb, parse_cancel))(input)
+    parse_message_with(input, Checksum::Additive)
+}
+
+/// Parses a message from a session using the given error detection scheme.
+pub fn parse_message_with(input: &[u8], checksum: Checksum) -> IResult<&[u8], Message> {
+    alt((
+        |input| parse_chunk(input, SOH, checksum).map(|(i, c)| (i, Message::Chunk(c))),
+        |input| parse_chunk(input, STX, checksum).map(|(i, c)| (i, Message::LargeChunk(c))),
+        parse_eot,
+        parse_etb,
+        parse_cancel,
+    ))(input)
 }
 
-fn parse_chunk(input: &[u8]) -> IResult<&[u8], Message> {
-    let (input, _) = tag(&[SOH])(input)?;
+fn parse_chunk<const N: usize>(
+    input: &[u8],
+    header: u8,
+    checksum: Checksum,
+) -> IResult<&[u8], Chunk<N>> {
+    let (input, _) = tag(&[header])(input)?;
     let (input, block_number) = be_u8(input)?;
     let (input, _) = tag(&[!block_number])(input)?;
-    let (input, payload) = take(PAYLOAD_SIZE)(input)?;
-    let checksum: u8 = payload.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
-    let (input, _) = tag(&[checksum])(input)?;
-    Ok((input, Message::Chunk(Chunk { block_number, payload: payload.try_into().unwrap() })))
+    let (input, payload) = take(N)(input)?;
+    let (input, _) = match checksum {
+        Checksum::Additive => tag(&[additive_checksum(payload)])(input)?,
+        Checksum::Crc16 => tag(&crc16(payload).to_be_bytes())(input)?,
+    };
+    Ok((input, Chunk { block_number, payload: payload.try_into().unwrap() }))
+}
+
+/// Encodes a packet carrying the payload into the buffer, returning its size.
+/// Payloads of `PAYLOAD_SIZE` are sent as `SOH` packets and any other as `STX`.
+pub(crate) fn encode_packet(
+    block_number: u8,
+    payload: &[u8],
+    checksum: Checksum,
+    buffer: &mut [u8],
+) -> usize {
+    let header = if payload.len() == PAYLOAD_SIZE { SOH } else { STX };
+    let size = 3 + payload.len() + checksum.size();
+    buffer[..3].copy_from_slice(&[header, block_number, !block_number]);
+    buffer[3..3 + payload.len()].copy_from_slice(payload);
+    match checksum {
+        Checksum::Additive => buffer[size - 1] = additive_checksum(payload),
+        Checksum::Crc16 => buffer[size - 2..size].copy_from_slice(&crc16(payload).to_be_bytes()),
+    }
+    size
+}
+
+fn additive_checksum(bytes: &[u8]) -> u8 { bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) }
+
+/// CRC-16/XMODEM (polynomial 0x1021, zero initial value, no reflection).
+fn crc16(bytes: &[u8]) -> u16 {
+    const POLYNOMIAL: u16 = 0x1021;
+    bytes.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ POLYNOMIAL
+            } else {
+                crc << 1
+            }
+        })
+    })
 }
 
 fn parse_eot(input: &[u8]) -> IResult<&[u8], Message> {
@@ -65,7 +173,7 @@ fn parse_cancel(input: &[u8]) -> IResult<&[u8], Message> {
 mod test {
     use super::*;
     use nom::Err::Incomplete;
-    const MAX_PACKET_SIZE: usize = 132;
+    const CLASSIC_PACKET_SIZE: usize = 132;
 
     fn write_test_packet(index: u8, payload_value: u8, buffer: &mut [u8]) {
         let checksum = (0..128).fold(0, |sum: u8, _| sum.wrapping_add(payload_value));
@@ -81,6 +189,33 @@ mod test {
         });
     }
 
+    fn write_test_packet_with(
+        header: u8,
+        index: u8,
+        payload_value: u8,
+        checksum: Checksum,
+        buffer: &mut [u8],
+    ) -> usize {
+        let payload_size = if header == STX { LARGE_PAYLOAD_SIZE } else { PAYLOAD_SIZE };
+        let payload = vec![payload_value; payload_size];
+        buffer[0] = header;
+        buffer[1] = index;
+        buffer[2] = !index;
+        buffer[3..3 + payload_size].copy_from_slice(&payload);
+        let trailer = &mut buffer[3 + payload_size..3 + payload_size + checksum.size()];
+        match checksum {
+            Checksum::Additive => trailer[0] = additive_checksum(&payload),
+            Checksum::Crc16 => trailer.copy_from_slice(&crc16(&payload).to_be_bytes()),
+        }
+        3 + payload_size + checksum.size()
+    }
+
+    #[test]
+    fn crc16_matches_reference_check_value() {
+        assert_eq!(0x31C3, crc16(b"123456789"));
+        assert_eq!(0x0000, crc16(&[]));
+    }
+
     #[test]
     fn parsing_single_character_control_messages() {
         let input = [EOT];
@@ -101,7 +236,7 @@ mod test {
 
     #[test]
     fn parsing_complete_input_chunk() {
-        let mut input = [0u8; MAX_PACKET_SIZE];
+        let mut input = [0u8; CLASSIC_PACKET_SIZE];
         write_test_packet(7, 42, &mut input);
         let (input, message) = parse_message(&input).unwrap();
 
@@ -117,17 +252,17 @@ mod test {
 
     #[test]
     fn parsing_incomplete_input_chunk() {
-        let mut input = [0u8; MAX_PACKET_SIZE / 2];
+        let mut input = [0u8; CLASSIC_PACKET_SIZE / 2];
         write_test_packet(7, 42, &mut input);
         assert!(parse_message(&input).unwrap_err().is_incomplete());
     }
 
     #[test]
     fn parsing_three_messages_in_a_row() {
-        let mut input = [0u8; 2 * MAX_PACKET_SIZE + 1];
+        let mut input = [0u8; 2 * CLASSIC_PACKET_SIZE + 1];
         write_test_packet(1, 1, &mut input);
-        write_test_packet(2, 2, &mut input[MAX_PACKET_SIZE..]);
-        input[2 * MAX_PACKET_SIZE] = EOT;
+        write_test_packet(2, 2, &mut input[CLASSIC_PACKET_SIZE..]);
+        input[2 * CLASSIC_PACKET_SIZE] = EOT;
 
         let (input, message) = parse_message(&input).unwrap();
         assert_eq!(
@@ -143,4 +278,60 @@ mod test {
         assert_eq!(Message::EndOfTransmission, message);
         assert_eq!(input.len(), 0);
     }
+
+    #[test]
+    fn parsing_crc_chunk() {
+        let mut input = [0u8; MAX_LARGE_PACKET_SIZE];
+        let size = write_test_packet_with(SOH, 7, 42, Checksum::Crc16, &mut input);
+        let (input, message) = parse_message_with(&input[..size], Checksum::Crc16).unwrap();
+
+        assert_eq!(
+            Message::Chunk(Chunk { payload: [42u8; PAYLOAD_SIZE], block_number: 7 }),
+            message
+        );
+        assert_eq!(input.len(), 0);
+    }
+
+    #[test]
+    fn parsing_large_chunks_with_either_checksum() {
+        for checksum in [Checksum::Additive, Checksum::Crc16].iter().copied() {
+            let mut input = [0u8; MAX_LARGE_PACKET_SIZE];
+            let size = write_test_packet_with(STX, 3, 0xAB, checksum, &mut input);
+            let (input, message) = parse_message_with(&input[..size], checksum).unwrap();
+
+            assert_eq!(
+                Message::LargeChunk(Chunk {
+                    payload: [0xABu8; LARGE_PAYLOAD_SIZE],
+                    block_number: 3
+                }),
+                message
+            );
+            assert_eq!(input.len(), 0);
+        }
+    }
+
+    #[test]
+    fn parsing_incomplete_large_chunk() {
+        let mut input = [0u8; MAX_LARGE_PACKET_SIZE];
+        write_test_packet_with(STX, 1, 1, Checksum::Crc16, &mut input);
+        assert!(parse_message_with(&input[..PAYLOAD_SIZE], Checksum::Crc16)
+            .unwrap_err()
+            .is_incomplete());
+        assert!(parse_message_with(&input[..MAX_LARGE_PACKET_SIZE - 1], Checksum::Crc16)
+            .unwrap_err()
+            .is_incomplete());
+    }
+
+    #[test]
+    fn rejecting_chunks_with_corrupted_crc() {
+        let mut input = [0u8; MAX_LARGE_PACKET_SIZE];
+        let size = write_test_packet_with(SOH, 1, 0x55, Checksum::Crc16, &mut input);
+        input[size - 1] ^= 0x01;
+        let error = parse_message_with(&input[..size], Checksum::Crc16).unwrap_err();
+        assert!(!error.is_incomplete());
+
+        // A CRC packet is not valid in a session using additive checksums
+        let size = write_test_packet_with(SOH, 1, 0x55, Checksum::Crc16, &mut input);
+        assert!(parse_message(&input[..size]).is_err());
+    }
 }
@@ -0,0 +1,446 @@
+//! Reusable XMODEM receive loop.
+//!
+//! Takes care of the session handshake, acknowledgement of every block,
+//! retransmission requests on timeouts or corrupted packets, duplicate
+//! blocks, cancellation and the final `EOT` handshake. Received payloads
+//! are handed over in order to a sink, which can be a flash device.
+use super::{
+    parse_message_with, Checksum, Message, ACK, CAN, DEFAULT_TIMEOUT, MAX_LARGE_PACKET_SIZE, NAK,
+};
+use crate::hal::{flash, serial, time::Milliseconds};
+
+/// Consecutive failed attempts to receive a block before giving up.
+pub const MAX_RETRIES: usize = 10;
+
+/// Summary of a completed transfer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    /// Number of blocks handed to the sink.
+    pub blocks: usize,
+    /// Number of payload bytes transferred. Senders count the data only,
+    /// while receivers also count any padding added to the last block.
+    pub bytes: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    /// No valid packet was received after `MAX_RETRIES` attempts.
+    TooManyRetries,
+    /// The sender cancelled the transfer.
+    Cancelled,
+    /// A block arrived that was neither the expected one nor a repeat of the
+    /// previous one, so the transfer was aborted.
+    OutOfSequence { expected: u8, received: u8 },
+    /// The sink failed to store a payload, so the transfer was aborted.
+    Sink(E),
+    /// Failed to write to the serial port.
+    Serial,
+}
+
+/// Whether the session continues after a block is handed to the sink.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Flow {
+    Continue,
+    /// Ends the session as soon as the block is acknowledged.
+    Stop,
+}
+
+/// Rules that vary between protocols built on top of XMODEM.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Session {
+    /// Number of the first block expected.
+    pub first_block: u8,
+    /// Follows a session whose last block may be repeated, if its `ACK` was
+    /// lost, before the first block of this one.
+    pub follows_block: bool,
+    /// Answers the first `EOT` with a `NAK`, only ending the session when the
+    /// sender confirms it with a second one.
+    pub confirm_end: bool,
+    /// Falls back from CRC-16 to additive checksums if the sender doesn't
+    /// answer the first few requests.
+    pub checksum_fallback: bool,
+    /// Keeps requesting the session for as long as the line stays silent,
+    /// instead of giving up after `MAX_RETRIES` timeouts (e.g. while a user
+    /// starts the sender by hand). Corrupt packets still count as retries.
+    pub wait_for_sender: bool,
+}
+
+const XMODEM_SESSION: Session = Session {
+    first_block: 1,
+    follows_block: false,
+    confirm_end: false,
+    checksum_fallback: true,
+    wait_for_sender: false,
+};
+
+/// Reason a packet could not be received.
+enum Fault {
+    Timeout,
+    Corrupt,
+}
+
+/// XMODEM receiver, generic over any serial port with timeouts.
+pub struct Receiver<'a, S>
+where
+    S: serial::WriteByte + serial::TimeoutRead,
+{
+    serial: &'a mut S,
+    checksum: Checksum,
+    timeout: Milliseconds,
+}
+
+impl<'a, S> Receiver<'a, S>
+where
+    S: serial::WriteByte + serial::TimeoutRead,
+{
+    /// Creates a receiver that requests the given error detection scheme.
+    /// A receiver requesting CRC-16 falls back to additive checksums if the
+    /// sender does not respond to the first few requests.
+    pub fn new(serial: &'a mut S, checksum: Checksum) -> Self {
+        Self::with_timeout(serial, checksum, DEFAULT_TIMEOUT.into())
+    }
+
+    pub fn with_timeout(serial: &'a mut S, checksum: Checksum, timeout: Milliseconds) -> Self {
+        Self { serial, checksum, timeout }
+    }
+
+    /// Error detection scheme in use, which may change during the handshake.
+    pub fn checksum(&self) -> Checksum { self.checksum }
+
+    /// Receives a whole transfer, handing every new payload to the sink in order.
+    pub fn receive<E, F>(&mut self, mut sink: F) -> Result<Transfer, Error<E>>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        self.receive_session(XMODEM_SESSION, |payload| sink(payload).map(|_| Flow::Continue))
+    }
+
+    /// Receives a session following the given rules, handing every new
+    /// payload to the sink in order until the sender ends the transmission
+    /// or the sink stops it.
+    pub(crate) fn receive_session<E, F>(
+        &mut self,
+        session: Session,
+        mut sink: F,
+    ) -> Result<Transfer, Error<E>>
+    where
+        F: FnMut(&[u8]) -> Result<Flow, E>,
+    {
+        let mut buffer = [0u8; MAX_LARGE_PACKET_SIZE];
+        let mut transfer = Transfer { blocks: 0, bytes: 0 };
+        let mut expected = session.first_block;
+        let mut retries = 0usize;
+        let mut end_requested = false;
+        self.send(self.checksum.request())?;
+
+        loop {
+            let message = self.next_message(&mut buffer);
+            let (block_number, payload) = match &message {
+                Ok(Message::Chunk(chunk)) => (chunk.block_number, &chunk.payload[..]),
+                Ok(Message::LargeChunk(chunk)) => (chunk.block_number, &chunk.payload[..]),
+                Ok(Message::EndOfTransmission) if session.confirm_end && !end_requested => {
+                    end_requested = true;
+                    self.send(NAK)?;
+                    continue;
+                }
+                Ok(Message::EndOfTransmission) => {
+                    self.send(ACK)?;
+                    return Ok(transfer);
+                }
+                Ok(Message::EndOfTransmissionBlock) => {
+                    self.send(ACK)?;
+                    continue;
+                }
+                Ok(Message::Cancel) if self.read_byte() == Some(CAN) => {
+                    return Err(Error::Cancelled)
+                }
+                Ok(Message::Cancel) | Err(_) => {
+                    let started = transfer.blocks > 0;
+                    let silent = matches!(message, Err(Fault::Timeout));
+                    if started || !silent || !session.wait_for_sender {
+                        retries += 1;
+                    }
+                    if retries >= MAX_RETRIES {
+                        self.cancel()?;
+                        return Err(Error::TooManyRetries);
+                    }
+                    let fall_back = session.checksum_fallback && self.checksum == Checksum::Crc16;
+                    if !started && fall_back && retries >= MAX_RETRIES / 2 {
+                        self.checksum = Checksum::Additive;
+                    }
+                    self.send(if started { NAK } else { self.checksum.request() })?;
+                    continue;
+                }
+            };
+
+            if block_number == expected {
+                let flow = match sink(payload) {
+                    Ok(flow) => flow,
+                    Err(e) => {
+                        self.cancel()?;
+                        return Err(Error::Sink(e));
+                    }
+                };
+                transfer.blocks += 1;
+                transfer.bytes += payload.len();
+                expected = expected.wrapping_add(1);
+                retries = 0;
+                self.send(ACK)?;
+                if flow == Flow::Stop {
+                    return Ok(transfer);
+                }
+            } else if (transfer.blocks > 0 || session.follows_block)
+                && block_number == expected.wrapping_sub(1)
+            {
+                // Our previous ACK was lost, so the sender repeated the block.
+                self.send(ACK)?;
+                if transfer.blocks == 0 {
+                    // It then waits for this session to be requested again.
+                    self.send(self.checksum.request())?;
+                }
+            } else {
+                self.cancel()?;
+                return Err(Error::OutOfSequence { expected, received: block_number });
+            }
+        }
+    }
+
+    /// Receives a whole transfer, writing it to flash starting at the given address.
+    pub fn receive_to_flash<F: flash::ReadWrite>(
+        &mut self,
+        flash: &mut F,
+        address: F::Address,
+    ) -> Result<Transfer, Error<F::Error>> {
+        let mut offset = 0usize;
+        self.receive(|payload| {
+            nb::block!(flash.write(address + offset, payload))?;
+            offset += payload.len();
+            Ok(())
+        })
+    }
+
+    /// Reads bytes until they form a complete message.
+    fn next_message(&mut self, buffer: &mut [u8; MAX_LARGE_PACKET_SIZE]) -> Result<Message, Fault> {
+        for size in 1..=buffer.len() {
+            buffer[size - 1] = self.read_byte().ok_or(Fault::Timeout)?;
+            match parse_message_with(&buffer[..size], self.checksum) {
+                Ok((_, message)) => return Ok(message),
+                Err(nom::Err::Incomplete(_)) => continue,
+                Err(_) => break,
+            }
+        }
+
+        // Let the line go quiet so the retransmission starts from a clean slate.
+        while self.read_byte().is_some() {}
+        Err(Fault::Corrupt)
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        serial::TimeoutRead::read(self.serial, self.timeout).ok()
+    }
+
+    fn send<E>(&mut self, byte: u8) -> Result<(), Error<E>> {
+        nb::block!(self.serial.write_byte(byte)).map_err(|_| Error::Serial)
+    }
+
+    fn cancel<E>(&mut self) -> Result<(), Error<E>> {
+        self.send(CAN)?;
+        self.send(CAN)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        hal::{
+            doubles::{flash::*, serial::*},
+            flash::ReadWrite,
+        },
+        utilities::xmodem::{
+            additive_checksum, crc16, CRC_REQUEST, EOT, LARGE_PAYLOAD_SIZE, PAYLOAD_SIZE, SOH, STX,
+        },
+    };
+
+    fn packet(header: u8, block_number: u8, payload: &[u8], checksum: Checksum) -> Vec<u8> {
+        let mut packet = vec![header, block_number, !block_number];
+        packet.extend_from_slice(payload);
+        match checksum {
+            Checksum::Additive => packet.push(additive_checksum(payload)),
+            Checksum::Crc16 => packet.extend_from_slice(&crc16(payload).to_be_bytes()),
+        }
+        packet
+    }
+
+    fn receive_all(
+        serial: &mut ScriptedSerial,
+        checksum: Checksum,
+    ) -> (Result<Transfer, Error<()>>, Vec<u8>) {
+        let mut received = Vec::new();
+        let result = Receiver::new(serial, checksum).receive(|payload| {
+            received.extend_from_slice(payload);
+            Ok(())
+        });
+        (result, received)
+    }
+
+    #[test]
+    fn receiving_a_transfer_acknowledges_every_block() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(SOH, 1, &[0x11; PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&packet(SOH, 2, &[0x22; PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&[EOT]);
+
+        // When
+        let (result, received) = receive_all(&mut serial, Checksum::Crc16);
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 2, bytes: 2 * PAYLOAD_SIZE }), result);
+        assert_eq!(&received[..PAYLOAD_SIZE], &[0x11; PAYLOAD_SIZE][..]);
+        assert_eq!(&received[PAYLOAD_SIZE..], &[0x22; PAYLOAD_SIZE][..]);
+        assert_eq!(serial.outgoing, vec![CRC_REQUEST, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn receiving_large_blocks_with_additive_checksum() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(STX, 1, &[0xAB; LARGE_PAYLOAD_SIZE], Checksum::Additive));
+        serial.queue(&packet(SOH, 2, &[0xCD; PAYLOAD_SIZE], Checksum::Additive));
+        serial.queue(&[EOT]);
+
+        // When
+        let (result, received) = receive_all(&mut serial, Checksum::Additive);
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 2, bytes: LARGE_PAYLOAD_SIZE + PAYLOAD_SIZE }), result);
+        assert_eq!(received.len(), LARGE_PAYLOAD_SIZE + PAYLOAD_SIZE);
+        assert_eq!(serial.outgoing, vec![NAK, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn duplicate_blocks_are_acknowledged_but_not_stored() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(SOH, 1, &[0x11; PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&packet(SOH, 1, &[0x11; PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&[EOT]);
+
+        // When
+        let (result, received) = receive_all(&mut serial, Checksum::Crc16);
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 1, bytes: PAYLOAD_SIZE }), result);
+        assert_eq!(received.len(), PAYLOAD_SIZE);
+        assert_eq!(serial.outgoing, vec![CRC_REQUEST, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn corrupted_packets_are_rejected_until_retransmitted() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(SOH, 1, &[0x11; PAYLOAD_SIZE], Checksum::Crc16));
+        let mut corrupted = packet(SOH, 2, &[0x22; PAYLOAD_SIZE], Checksum::Crc16);
+        corrupted[10] ^= 0xFF;
+        serial.queue(&corrupted);
+        serial.queue_timeout();
+        serial.queue(&packet(SOH, 2, &[0x22; PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&[EOT]);
+
+        // When
+        let (result, received) = receive_all(&mut serial, Checksum::Crc16);
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 2, bytes: 2 * PAYLOAD_SIZE }), result);
+        assert_eq!(&received[PAYLOAD_SIZE..], &[0x22; PAYLOAD_SIZE][..]);
+        assert_eq!(serial.outgoing, vec![CRC_REQUEST, ACK, NAK, ACK, ACK]);
+    }
+
+    #[test]
+    fn sender_cancels_the_transfer() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(SOH, 1, &[0x11; PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&[CAN, CAN]);
+
+        // When
+        let (result, _) = receive_all(&mut serial, Checksum::Crc16);
+
+        // Then
+        assert_eq!(Err(Error::Cancelled), result);
+        assert_eq!(serial.outgoing, vec![CRC_REQUEST, ACK]);
+    }
+
+    #[test]
+    fn out_of_sequence_blocks_abort_the_transfer() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(SOH, 1, &[0x11; PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&packet(SOH, 3, &[0x33; PAYLOAD_SIZE], Checksum::Crc16));
+
+        // When
+        let (result, _) = receive_all(&mut serial, Checksum::Crc16);
+
+        // Then
+        assert_eq!(Err(Error::OutOfSequence { expected: 2, received: 3 }), result);
+        assert_eq!(serial.outgoing, vec![CRC_REQUEST, ACK, CAN, CAN]);
+    }
+
+    #[test]
+    fn silent_sender_exhausts_retries_after_falling_back_to_additive_checksum() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+
+        // When
+        let mut receiver = Receiver::new(&mut serial, Checksum::Crc16);
+        let result = receiver.receive(|_| Ok::<(), ()>(()));
+
+        // Then
+        assert_eq!(Err(Error::TooManyRetries), result);
+        assert_eq!(Checksum::Additive, receiver.checksum());
+        let requests = &serial.outgoing[..MAX_RETRIES];
+        assert_eq!(requests.iter().filter(|b| **b == CRC_REQUEST).count(), MAX_RETRIES / 2);
+        assert_eq!(requests.iter().filter(|b| **b == NAK).count(), MAX_RETRIES / 2);
+        assert_eq!(&serial.outgoing[MAX_RETRIES..], &[CAN, CAN]);
+    }
+
+    #[test]
+    fn sink_errors_abort_the_transfer() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(SOH, 1, &[0x11; PAYLOAD_SIZE], Checksum::Crc16));
+
+        // When
+        let result = Receiver::new(&mut serial, Checksum::Crc16).receive(|_| Err("Full"));
+
+        // Then
+        assert_eq!(Err(Error::Sink("Full")), result);
+        assert_eq!(serial.outgoing, vec![CRC_REQUEST, CAN, CAN]);
+    }
+
+    #[test]
+    fn receiving_into_flash() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(STX, 1, &[0x11; LARGE_PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&packet(STX, 2, &[0x22; LARGE_PAYLOAD_SIZE], Checksum::Crc16));
+        serial.queue(&[EOT]);
+        let mut flash = FakeFlash::new(Address(0));
+        let address = Address(0x100);
+
+        // When
+        let result =
+            Receiver::new(&mut serial, Checksum::Crc16).receive_to_flash(&mut flash, address);
+
+        // Then
+        assert_eq!(
+            Ok(Transfer { blocks: 2, bytes: 2 * LARGE_PAYLOAD_SIZE }),
+            result.map_err(|_| ())
+        );
+        let mut stored = [0u8; 2 * LARGE_PAYLOAD_SIZE];
+        flash.read(address, &mut stored).unwrap();
+        assert!(stored[..LARGE_PAYLOAD_SIZE].iter().all(|b| *b == 0x11));
+        assert!(stored[LARGE_PAYLOAD_SIZE..].iter().all(|b| *b == 0x22));
+    }
+}
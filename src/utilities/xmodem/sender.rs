@@ -0,0 +1,330 @@
+//! XMODEM transmitter.
+//!
+//! Waits for the receiver to start the session, which also decides the error
+//! detection scheme, then sends the data in numbered blocks. Blocks are
+//! retransmitted on `NAK` or silence, the last one is padded with `SUB`, and
+//! the transfer finishes with the `EOT` handshake.
+use super::{
+    encode_packet, Checksum, Transfer, ACK, CAN, CRC_REQUEST, DEFAULT_TIMEOUT, EOT,
+    LARGE_PAYLOAD_SIZE, MAX_LARGE_PACKET_SIZE, MAX_RETRIES, NAK, PAYLOAD_SIZE, SUB,
+};
+use crate::{
+    hal::{flash, serial, time::Milliseconds},
+    utilities::buffer::CollectSlice,
+};
+
+/// Payload size of the packets sent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockSize {
+    /// 128 byte payloads, understood by every receiver.
+    Standard,
+    /// XMODEM-1K payloads. Only used if the receiver requests CRC-16, as
+    /// receivers relying on additive checksums predate the extension.
+    Large,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    /// The receiver did not start the session or acknowledge a packet after
+    /// `MAX_RETRIES` attempts.
+    TooManyRetries,
+    /// The receiver cancelled the transfer.
+    Cancelled,
+    /// Failed to read the data to send, so the transfer was aborted.
+    Source(E),
+    /// Failed to write to the serial port.
+    Serial,
+}
+
+/// XMODEM sender, generic over any serial port with timeouts.
+pub struct Sender<'a, S>
+where
+    S: serial::WriteByte + serial::TimeoutRead,
+{
+    serial: &'a mut S,
+    block_size: BlockSize,
+    timeout: Milliseconds,
+}
+
+impl<'a, S> Sender<'a, S>
+where
+    S: serial::WriteByte + serial::TimeoutRead,
+{
+    pub fn new(serial: &'a mut S, block_size: BlockSize) -> Self {
+        Self::with_timeout(serial, block_size, DEFAULT_TIMEOUT.into())
+    }
+
+    pub fn with_timeout(serial: &'a mut S, block_size: BlockSize, timeout: Milliseconds) -> Self {
+        Self { serial, block_size, timeout }
+    }
+
+    /// Sends every byte yielded by the iterator. The returned transfer counts
+    /// the bytes sent, excluding padding.
+    pub fn send<I: Iterator<Item = u8>>(&mut self, mut bytes: I) -> Result<Transfer, Error<!>> {
+        self.send_with(|payload| Ok(bytes.collect_slice(payload)))
+    }
+
+    /// Sends `size` bytes of flash, starting at the given address and stopping
+    /// at the end of the device. The returned transfer counts the bytes sent,
+    /// excluding padding.
+    pub fn send_from_flash<F: flash::ReadWrite>(
+        &mut self,
+        flash: &mut F,
+        address: F::Address,
+        size: usize,
+    ) -> Result<Transfer, Error<F::Error>> {
+        let mut reader = flash.reader(address, size);
+        self.send_with(|payload| reader.read(payload))
+    }
+
+    /// Sends payloads produced by `fill`, which returns how many bytes it
+    /// wrote. Fewer bytes than requested mark the end of the data.
+    fn send_with<E, F>(&mut self, mut fill: F) -> Result<Transfer, Error<E>>
+    where
+        F: FnMut(&mut [u8]) -> Result<usize, E>,
+    {
+        let checksum = self.await_request()?;
+        let payload_size = match (self.block_size, checksum) {
+            (BlockSize::Large, Checksum::Crc16) => LARGE_PAYLOAD_SIZE,
+            _ => PAYLOAD_SIZE,
+        };
+
+        let mut payload = [0u8; LARGE_PAYLOAD_SIZE];
+        let mut packet = [0u8; MAX_LARGE_PACKET_SIZE];
+        let mut transfer = Transfer { blocks: 0, bytes: 0 };
+        let mut block_number = 1u8;
+
+        loop {
+            let count = match fill(&mut payload[..payload_size]) {
+                Ok(count) => count,
+                Err(e) => {
+                    self.cancel()?;
+                    return Err(Error::Source(e));
+                }
+            };
+            if count == 0 {
+                break;
+            }
+
+            // A short last block is sent in a standard packet to save on padding.
+            let size = if count <= PAYLOAD_SIZE { PAYLOAD_SIZE } else { payload_size };
+            payload[count..size].iter_mut().for_each(|b| *b = SUB);
+            let packet_size = encode_packet(block_number, &payload[..size], checksum, &mut packet);
+            self.transmit(&packet[..packet_size])?;
+
+            transfer.blocks += 1;
+            transfer.bytes += count;
+            block_number = block_number.wrapping_add(1);
+            if count < payload_size {
+                break;
+            }
+        }
+
+        self.transmit(&[EOT])?;
+        Ok(transfer)
+    }
+
+    /// Waits for the receiver to start the session, returning the requested
+    /// error detection scheme.
+    fn await_request<E>(&mut self) -> Result<Checksum, Error<E>> {
+        for _ in 0..MAX_RETRIES {
+            match self.read_byte() {
+                Some(CRC_REQUEST) => return Ok(Checksum::Crc16),
+                Some(NAK) => return Ok(Checksum::Additive),
+                Some(CAN) if self.read_byte() == Some(CAN) => return Err(Error::Cancelled),
+                _ => continue,
+            }
+        }
+        Err(Error::TooManyRetries)
+    }
+
+    /// Sends a packet until the receiver acknowledges it.
+    fn transmit<E>(&mut self, packet: &[u8]) -> Result<(), Error<E>> {
+        for _ in 0..MAX_RETRIES {
+            for byte in packet {
+                self.send_byte(*byte)?;
+            }
+            match self.read_byte() {
+                Some(ACK) => return Ok(()),
+                Some(CAN) if self.read_byte() == Some(CAN) => return Err(Error::Cancelled),
+                // Anything else (NAK, noise or silence) prompts a retransmission.
+                _ => continue,
+            }
+        }
+        self.cancel()?;
+        Err(Error::TooManyRetries)
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        serial::TimeoutRead::read(self.serial, self.timeout).ok()
+    }
+
+    fn send_byte<E>(&mut self, byte: u8) -> Result<(), Error<E>> {
+        nb::block!(self.serial.write_byte(byte)).map_err(|_| Error::Serial)
+    }
+
+    fn cancel<E>(&mut self) -> Result<(), Error<E>> {
+        self.send_byte(CAN)?;
+        self.send_byte(CAN)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        hal::{
+            doubles::{flash::*, serial::*},
+            flash::ReadWrite,
+        },
+        utilities::xmodem::{parse_message_with, Chunk, Message, STX},
+    };
+
+    fn parse_all(mut input: &[u8], checksum: Checksum) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while !input.is_empty() {
+            let (rest, message) = parse_message_with(input, checksum).unwrap();
+            messages.push(message);
+            input = rest;
+        }
+        messages
+    }
+
+    #[test]
+    fn sending_standard_blocks_with_crc_pads_the_last_block() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&[CRC_REQUEST, ACK, ACK, ACK]);
+        let data = [0x42u8; PAYLOAD_SIZE + 10];
+
+        // When
+        let result = Sender::new(&mut serial, BlockSize::Standard).send(data.iter().copied());
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 2, bytes: PAYLOAD_SIZE + 10 }), result);
+        let messages = parse_all(&serial.outgoing, Checksum::Crc16);
+        let mut last_payload = [SUB; PAYLOAD_SIZE];
+        last_payload[..10].copy_from_slice(&[0x42; 10]);
+        assert_eq!(
+            messages,
+            vec![
+                Message::Chunk(Chunk { block_number: 1, payload: [0x42; PAYLOAD_SIZE] }),
+                Message::Chunk(Chunk { block_number: 2, payload: last_payload }),
+                Message::EndOfTransmission,
+            ]
+        );
+    }
+
+    #[test]
+    fn sending_large_blocks_finishes_with_a_standard_block_when_possible() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&[CRC_REQUEST, ACK, ACK, ACK]);
+        let data = [0x42u8; LARGE_PAYLOAD_SIZE + 1];
+
+        // When
+        let result = Sender::new(&mut serial, BlockSize::Large).send(data.iter().copied());
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 2, bytes: LARGE_PAYLOAD_SIZE + 1 }), result);
+        assert_eq!(serial.outgoing[0], STX);
+        let messages = parse_all(&serial.outgoing, Checksum::Crc16);
+        assert!(matches!(messages[0], Message::LargeChunk(Chunk { block_number: 1, .. })));
+        assert!(matches!(messages[1], Message::Chunk(Chunk { block_number: 2, .. })));
+        assert_eq!(messages[2], Message::EndOfTransmission);
+    }
+
+    #[test]
+    fn large_blocks_are_not_sent_to_receivers_requesting_additive_checksums() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&[NAK]);
+        serial.queue(&[ACK; 9]);
+        let data = [0x42u8; LARGE_PAYLOAD_SIZE];
+
+        // When
+        let result = Sender::new(&mut serial, BlockSize::Large).send(data.iter().copied());
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 8, bytes: LARGE_PAYLOAD_SIZE }), result);
+        let messages = parse_all(&serial.outgoing, Checksum::Additive);
+        assert!(messages[..8].iter().all(|m| matches!(m, Message::Chunk(_))));
+    }
+
+    #[test]
+    fn packets_are_retransmitted_on_nak_or_silence() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&[CRC_REQUEST, NAK]);
+        serial.queue_timeout();
+        serial.queue(&[ACK, NAK, ACK]);
+        let data = [0x42u8; PAYLOAD_SIZE];
+
+        // When
+        let result = Sender::new(&mut serial, BlockSize::Standard).send(data.iter().copied());
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 1, bytes: PAYLOAD_SIZE }), result);
+        let messages = parse_all(&serial.outgoing, Checksum::Crc16);
+        assert_eq!(messages.len(), 5);
+        assert_eq!(messages[0], messages[1]);
+        assert_eq!(messages[1], messages[2]);
+        assert_eq!(messages[3], Message::EndOfTransmission);
+        assert_eq!(messages[4], Message::EndOfTransmission);
+    }
+
+    #[test]
+    fn receiver_cancels_the_transfer() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&[CRC_REQUEST, CAN, CAN]);
+        let data = [0x42u8; 2 * PAYLOAD_SIZE];
+
+        // When
+        let result = Sender::new(&mut serial, BlockSize::Standard).send(data.iter().copied());
+
+        // Then
+        assert_eq!(Err(Error::Cancelled), result);
+    }
+
+    #[test]
+    fn silent_receiver_exhausts_retries() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+
+        // When
+        let result =
+            Sender::new(&mut serial, BlockSize::Standard).send([1u8, 2, 3].iter().copied());
+
+        // Then
+        assert_eq!(Err(Error::TooManyRetries), result);
+        assert!(serial.outgoing.is_empty());
+    }
+
+    #[test]
+    fn sending_from_flash() {
+        // Given
+        let mut flash = FakeFlash::new(Address(0));
+        let data: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        flash.write(Address(0x400), &data).unwrap();
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&[CRC_REQUEST, ACK, ACK, ACK, ACK]);
+
+        // When
+        let result = Sender::new(&mut serial, BlockSize::Standard).send_from_flash(
+            &mut flash,
+            Address(0x400),
+            data.len(),
+        );
+
+        // Then
+        assert_eq!(Ok(Transfer { blocks: 3, bytes: 300 }), result.map_err(|_| ()));
+        let sent: Vec<u8> = parse_all(&serial.outgoing, Checksum::Crc16)
+            .into_iter()
+            .filter_map(|m| if let Message::Chunk(c) = m { Some(c.payload) } else { None })
+            .flat_map(|payload| payload.to_vec())
+            .collect();
+        assert_eq!(&sent[..300], &data[..]);
+        assert!(sent[300..].iter().all(|b| *b == SUB));
+    }
+}
@@ -0,0 +1,379 @@
+//! YMODEM batch transfers on top of the xmodem module.
+//!
+//! Every file in a batch is preceded by a header in block 0 carrying its name,
+//! its exact size and optionally its modification time, so the padding of the
+//! last block can be discarded. A header with an empty name ends the batch.
+use crate::{
+    hal::{serial, time::Milliseconds},
+    utilities::xmodem::{self, Checksum, Flow, ReceiveError, Session},
+};
+use core::{cmp::min, convert::TryInto};
+use nom::{
+    bytes::complete::{tag, take_until},
+    character::complete::{char, digit1, oct_digit1},
+    combinator::{map_opt, opt},
+    sequence::{preceded, terminated},
+    IResult,
+};
+
+pub const MAX_FILE_NAME_SIZE: usize = 64;
+
+/// YMODEM is CRC-16 only. Only the first header of a batch waits for the
+/// user to start the sender, see `receive`.
+const HEADER_SESSION: Session = Session {
+    first_block: 0,
+    follows_block: false,
+    confirm_end: false,
+    checksum_fallback: false,
+    wait_for_sender: false,
+};
+const DATA_SESSION: Session = Session {
+    first_block: 1,
+    follows_block: true,
+    confirm_end: true,
+    checksum_fallback: false,
+    wait_for_sender: false,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FileHeader {
+    name: [u8; MAX_FILE_NAME_SIZE],
+    name_size: usize,
+    /// Exact size of the file in bytes, if declared by the sender.
+    pub size: Option<usize>,
+    /// Modification time in seconds since the Unix epoch, if declared by the sender.
+    pub modification_time: Option<u32>,
+}
+
+impl FileHeader {
+    pub fn name(&self) -> &[u8] { &self.name[..self.name_size] }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The file name is not terminated, or the file information is not valid.
+    Malformed,
+    /// The file name is longer than `MAX_FILE_NAME_SIZE`.
+    NameTooLong,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    /// A file header could not be parsed, so the batch was aborted.
+    InvalidHeader(HeaderError),
+    /// The underlying XMODEM transfer failed.
+    Transfer(ReceiveError<E>),
+}
+
+/// Reasons for the receiver to abort a session.
+enum Rejection<E> {
+    Header(HeaderError),
+    Sink(E),
+}
+
+/// Parses the payload of block 0, returning `None` for the empty header
+/// that terminates a batch.
+pub fn parse_header(payload: &[u8]) -> Result<Option<FileHeader>, HeaderError> {
+    if payload.first().copied().unwrap_or(0) == 0 {
+        return Ok(None);
+    }
+
+    let (_, (name, size, modification_time)) =
+        header_fields(payload).map_err(|_| HeaderError::Malformed)?;
+    if name.len() > MAX_FILE_NAME_SIZE {
+        return Err(HeaderError::NameTooLong);
+    }
+
+    let mut header = FileHeader {
+        name: [0u8; MAX_FILE_NAME_SIZE],
+        name_size: name.len(),
+        size,
+        modification_time,
+    };
+    header.name[..name.len()].copy_from_slice(name);
+    Ok(Some(header))
+}
+
+/// File name, size and modification time.
+type HeaderFields<'a> = (&'a [u8], Option<usize>, Option<u32>);
+
+/// File name, followed by a null terminator, the decimal size and the octal
+/// modification time. Any further fields (such as the file mode) are ignored.
+fn header_fields(input: &[u8]) -> IResult<&[u8], HeaderFields<'_>> {
+    let (input, name) = terminated(take_until(&[0u8][..]), tag(&[0u8]))(input)?;
+    let (input, size) = opt(map_opt(digit1, |digits| parse_number(digits, 10)))(input)?;
+    let (input, modification_time) = opt(preceded(
+        char(' '),
+        map_opt(oct_digit1, |digits| parse_number(digits, 8)?.try_into().ok()),
+    ))(input)?;
+    Ok((input, (name, size, modification_time)))
+}
+
+fn parse_number(digits: &[u8], radix: u32) -> Option<usize> {
+    digits.iter().try_fold(0usize, |value, digit| {
+        let digit = (*digit as char).to_digit(radix)? as usize;
+        value.checked_mul(radix as usize)?.checked_add(digit)
+    })
+}
+
+/// YMODEM batch receiver, generic over any serial port with timeouts.
+pub struct Receiver<'a, S>
+where
+    S: serial::WriteByte + serial::TimeoutRead,
+{
+    inner: xmodem::Receiver<'a, S>,
+}
+
+impl<'a, S> Receiver<'a, S>
+where
+    S: serial::WriteByte + serial::TimeoutRead,
+{
+    pub fn new(serial: &'a mut S) -> Self {
+        Self { inner: xmodem::Receiver::new(serial, Checksum::Crc16) }
+    }
+
+    pub fn with_timeout(serial: &'a mut S, timeout: Milliseconds) -> Self {
+        Self { inner: xmodem::Receiver::with_timeout(serial, Checksum::Crc16, timeout) }
+    }
+
+    /// Receives a whole batch, returning the number of files received. The sink
+    /// is handed the contents of every file in order, alongside its header. The
+    /// padding of the last block is discarded for files that declare a size.
+    pub fn receive<E, F>(&mut self, mut sink: F) -> Result<usize, Error<E>>
+    where
+        F: FnMut(&FileHeader, &[u8]) -> Result<(), E>,
+    {
+        let mut files = 0usize;
+        loop {
+            let mut header = None;
+            let session = Session { wait_for_sender: files == 0, ..HEADER_SESSION };
+            self.inner.receive_session(session, |payload| {
+                header = parse_header(payload).map_err(Rejection::Header)?;
+                Ok(Flow::Stop)
+            })?;
+
+            let header = match header {
+                Some(header) => header,
+                None => return Ok(files),
+            };
+
+            let mut remaining = header.size.unwrap_or(usize::MAX);
+            self.inner.receive_session(DATA_SESSION, |payload| {
+                let bytes = &payload[..min(payload.len(), remaining)];
+                remaining -= bytes.len();
+                if !bytes.is_empty() {
+                    sink(&header, bytes).map_err(Rejection::Sink)?;
+                }
+                Ok(Flow::Continue)
+            })?;
+            files += 1;
+        }
+    }
+}
+
+impl<E> From<ReceiveError<Rejection<E>>> for Error<E> {
+    fn from(error: ReceiveError<Rejection<E>>) -> Self {
+        match error {
+            ReceiveError::Sink(Rejection::Header(e)) => Error::InvalidHeader(e),
+            ReceiveError::Sink(Rejection::Sink(e)) => Error::Transfer(ReceiveError::Sink(e)),
+            ReceiveError::TooManyRetries => Error::Transfer(ReceiveError::TooManyRetries),
+            ReceiveError::Cancelled => Error::Transfer(ReceiveError::Cancelled),
+            ReceiveError::OutOfSequence { expected, received } => {
+                Error::Transfer(ReceiveError::OutOfSequence { expected, received })
+            }
+            ReceiveError::Serial => Error::Transfer(ReceiveError::Serial),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        hal::doubles::serial::*,
+        utilities::xmodem::{
+            encode_packet, ACK, CAN, CRC_REQUEST, EOT, MAX_LARGE_PACKET_SIZE, NAK,
+        },
+    };
+
+    fn packet(block_number: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = [0u8; MAX_LARGE_PACKET_SIZE];
+        let size = encode_packet(block_number, payload, Checksum::Crc16, &mut buffer);
+        buffer[..size].to_vec()
+    }
+
+    fn header_payload(fields: &[u8]) -> [u8; xmodem::PAYLOAD_SIZE] {
+        let mut payload = [0u8; xmodem::PAYLOAD_SIZE];
+        payload[..fields.len()].copy_from_slice(fields);
+        payload
+    }
+
+    #[test]
+    fn parsing_complete_header() {
+        let payload = header_payload(b"image.bin\x0012345 14234567123 100644 0");
+        let header = parse_header(&payload).unwrap().unwrap();
+        assert_eq!(header.name(), b"image.bin");
+        assert_eq!(header.size, Some(12345));
+        assert_eq!(header.modification_time, Some(0o14234567123));
+    }
+
+    #[test]
+    fn parsing_headers_with_optional_fields_missing() {
+        let header = parse_header(&header_payload(b"log.txt\x00300")).unwrap().unwrap();
+        assert_eq!(header.name(), b"log.txt");
+        assert_eq!(header.size, Some(300));
+        assert_eq!(header.modification_time, None);
+
+        let header = parse_header(&header_payload(b"log.txt\x00")).unwrap().unwrap();
+        assert_eq!(header.size, None);
+    }
+
+    #[test]
+    fn parsing_batch_terminator() {
+        assert_eq!(Ok(None), parse_header(&[0u8; xmodem::PAYLOAD_SIZE]));
+    }
+
+    #[test]
+    fn rejecting_invalid_headers() {
+        assert_eq!(Err(HeaderError::Malformed), parse_header(&[b'a'; xmodem::PAYLOAD_SIZE]));
+        let long_name = [b'a'; MAX_FILE_NAME_SIZE + 1];
+        assert_eq!(Err(HeaderError::NameTooLong), parse_header(&header_payload(&long_name)));
+    }
+
+    #[test]
+    fn receiving_a_batch_truncates_every_file_to_its_size() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(0, &header_payload(b"first\x00130")));
+        serial.queue(&packet(1, &[0x11; xmodem::PAYLOAD_SIZE]));
+        serial.queue(&packet(2, &[0x11; xmodem::PAYLOAD_SIZE]));
+        serial.queue(&[EOT, EOT]);
+        serial.queue(&packet(0, &header_payload(b"second\x005")));
+        serial.queue(&packet(1, &[0x22; xmodem::PAYLOAD_SIZE]));
+        serial.queue(&[EOT, EOT]);
+        serial.queue(&packet(0, &[0u8; xmodem::PAYLOAD_SIZE]));
+
+        // When
+        let mut files: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let result = Receiver::new(&mut serial).receive(|header, bytes| {
+            match files.last_mut() {
+                Some((name, contents)) if name == header.name() => {
+                    contents.extend_from_slice(bytes)
+                }
+                _ => files.push((header.name().to_vec(), bytes.to_vec())),
+            }
+            Ok::<(), ()>(())
+        });
+
+        // Then
+        assert_eq!(Ok(2), result);
+        assert_eq!(files[0], (b"first".to_vec(), vec![0x11; 130]));
+        assert_eq!(files[1], (b"second".to_vec(), vec![0x22; 5]));
+        let file_exchange = [CRC_REQUEST, ACK, CRC_REQUEST, ACK, ACK, NAK, ACK];
+        assert_eq!(&serial.outgoing[..7], &file_exchange);
+        assert_eq!(
+            &serial.outgoing[7..],
+            &[CRC_REQUEST, ACK, CRC_REQUEST, ACK, NAK, ACK, CRC_REQUEST, ACK]
+        );
+    }
+
+    #[test]
+    fn repeated_headers_are_acknowledged_again() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(0, &header_payload(b"file\x003")));
+        serial.queue(&packet(0, &header_payload(b"file\x003")));
+        serial.queue(&packet(1, &[0x33; xmodem::PAYLOAD_SIZE]));
+        serial.queue(&[EOT, EOT]);
+        serial.queue(&packet(0, &[0u8; xmodem::PAYLOAD_SIZE]));
+
+        // When
+        let mut contents = Vec::new();
+        let result = Receiver::new(&mut serial).receive(|_, bytes| {
+            contents.extend_from_slice(bytes);
+            Ok::<(), ()>(())
+        });
+
+        // Then
+        assert_eq!(Ok(1), result);
+        assert_eq!(contents, vec![0x33; 3]);
+        assert_eq!(&serial.outgoing[..6], &[CRC_REQUEST, ACK, CRC_REQUEST, ACK, CRC_REQUEST, ACK]);
+    }
+
+    #[test]
+    fn waiting_for_the_sender_with_crc_requests_only() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        (0..2 * xmodem::MAX_RETRIES).for_each(|_| serial.queue_timeout());
+        serial.queue(&packet(0, &[0u8; xmodem::PAYLOAD_SIZE]));
+
+        // When
+        let result = Receiver::new(&mut serial).receive(|_, _| Ok::<(), ()>(()));
+
+        // Then
+        assert_eq!(Ok(0), result);
+        let (requests, answer) = serial.outgoing.split_at(2 * xmodem::MAX_RETRIES + 1);
+        assert!(requests.iter().all(|byte| *byte == CRC_REQUEST));
+        assert_eq!(answer, &[ACK]);
+    }
+
+    #[test]
+    fn a_noisy_line_between_files_ends_the_batch() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(0, &header_payload(b"file\x003")));
+        serial.queue(&packet(1, &[0x33; xmodem::PAYLOAD_SIZE]));
+        serial.queue(&[EOT, EOT]);
+        for _ in 0..xmodem::MAX_RETRIES {
+            serial.queue(&[0x42; 3]);
+            serial.queue_timeout();
+        }
+        serial.queue(&packet(0, &[0u8; xmodem::PAYLOAD_SIZE]));
+
+        // When
+        let result = Receiver::new(&mut serial).receive(|_, _| Ok::<(), ()>(()));
+
+        // Then
+        assert_eq!(Err(Error::Transfer(ReceiveError::TooManyRetries)), result);
+        assert!(serial.outgoing.ends_with(&[CAN, CAN]));
+    }
+
+    #[test]
+    fn garbage_counts_as_retries_while_waiting_for_the_sender() {
+        // Given
+        let mut serial = ScriptedSerial::new();
+        for _ in 0..xmodem::MAX_RETRIES {
+            serial.queue_timeout();
+            serial.queue(&[0x42; 3]);
+            serial.queue_timeout();
+        }
+
+        // When
+        let result = Receiver::new(&mut serial).receive(|_, _| Ok::<(), ()>(()));
+
+        // Then
+        assert_eq!(Err(Error::Transfer(ReceiveError::TooManyRetries)), result);
+    }
+
+    #[test]
+    fn receiving_an_empty_batch() {
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(0, &[0u8; xmodem::PAYLOAD_SIZE]));
+
+        let result = Receiver::new(&mut serial).receive(|_, _| Ok::<(), ()>(()));
+
+        assert_eq!(Ok(0), result);
+        assert_eq!(serial.outgoing, vec![CRC_REQUEST, ACK]);
+    }
+
+    #[test]
+    fn invalid_headers_abort_the_batch() {
+        let mut serial = ScriptedSerial::new();
+        serial.queue(&packet(0, &[b'a'; xmodem::PAYLOAD_SIZE]));
+
+        let result = Receiver::new(&mut serial).receive(|_, _| Ok::<(), ()>(()));
+
+        assert_eq!(Err(Error::InvalidHeader(HeaderError::Malformed)), result);
+        assert_eq!(serial.outgoing, vec![CRC_REQUEST, CAN, CAN]);
+    }
+}